@@ -0,0 +1,314 @@
+use crate::properties::Properties;
+use crate::ConfigError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::str::FromStr;
+
+/// `ARDUINO` when the platform doesn't set `runtime.ide.version`, the one arduino-cli passes
+const DEFAULT_IDE_VERSION: &str = "10607";
+
+/// Fully qualified board name, e.g. `arduino:avr:uno` or `arduino:avr:nano:cpu=atmega328old`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Fqbn {
+  /// Package vendor, the directory under `packages`
+  pub(crate) vendor: String,
+  /// Architecture, the directory under `hardware`
+  pub(crate) architecture: String,
+  /// Board id in `boards.txt`
+  pub(crate) board: String,
+  /// Chosen option for each board menu, e.g. `cpu` -> `atmega328old`
+  pub(crate) options: BTreeMap<String, String>,
+}
+
+impl FromStr for Fqbn {
+  type Err = ConfigError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || ConfigError::InvalidFqbn(s.to_string());
+    let (vendor, architecture, board, options) = match s.split(':').collect::<Vec<_>>()[..] {
+      [vendor, architecture, board] => (vendor, architecture, board, None),
+      [vendor, architecture, board, options] => (vendor, architecture, board, Some(options)),
+      _ => return Err(invalid()),
+    };
+    if vendor.is_empty() || architecture.is_empty() || board.is_empty() {
+      return Err(invalid());
+    }
+    let options = options
+      .into_iter()
+      .flat_map(|options| options.split(','))
+      .map(|option| match option.split_once('=') {
+        Some((menu, choice)) if !menu.is_empty() && !choice.is_empty() => {
+          Ok((menu.to_string(), choice.to_string()))
+        }
+        _ => Err(invalid()),
+      })
+      .collect::<Result<_, _>>()?;
+    Ok(Fqbn {
+      vendor: vendor.to_string(),
+      architecture: architecture.to_string(),
+      board: board.to_string(),
+      options,
+    })
+  }
+}
+
+/// A board's properties from `boards.txt`, with the board id prefix stripped, layered on
+/// top of the core's `platform.txt`
+#[derive(Debug)]
+pub(crate) struct Board {
+  fqbn: Fqbn,
+  properties: Properties,
+}
+
+impl Board {
+  /// Looks up the board named by `fqbn` in a core's `boards.txt` and applies the
+  /// `menu.<menu>.<option>` overrides for the options it selects
+  ///
+  /// Every menu the board defines must have an option selected
+  pub(crate) fn load(
+    boards_txt: &Path,
+    fqbn: Fqbn,
+    platform: Properties,
+  ) -> Result<Self, ConfigError> {
+    let board = Properties::load(boards_txt)?.subtree(&fqbn.board);
+    if board.get("name").is_none() {
+      return Err(ConfigError::UnknownBoard(
+        fqbn.board,
+        boards_txt.to_path_buf(),
+      ));
+    }
+
+    let menus = board.subtree("menu");
+    let mut choices: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for key in menus.keys() {
+      let mut parts = key.split('.');
+      if let (Some(menu), Some(option)) = (parts.next(), parts.next()) {
+        choices.entry(menu).or_default().insert(option);
+      }
+    }
+    if let Some(menu) = fqbn
+      .options
+      .keys()
+      .find(|menu| !choices.contains_key(menu.as_str()))
+    {
+      return Err(ConfigError::UnknownBoardMenu(
+        fqbn.board.clone(),
+        menu.clone(),
+      ));
+    }
+    let mut overrides = Vec::new();
+    for (menu, options) in &choices {
+      let valid = || options.iter().map(|option| option.to_string()).collect();
+      let option = fqbn.options.get(*menu).ok_or_else(|| {
+        ConfigError::MissingBoardOption(fqbn.board.clone(), menu.to_string(), valid())
+      })?;
+      if !options.contains(option.as_str()) {
+        return Err(ConfigError::InvalidBoardOption(
+          fqbn.board.clone(),
+          menu.to_string(),
+          option.clone(),
+          valid(),
+        ));
+      }
+      overrides.push(menus.subtree(&format!("{}.{}", menu, option)));
+    }
+    let mut properties = platform;
+    properties.extend(board);
+    for menu_override in overrides {
+      properties.extend(menu_override);
+    }
+    properties.insert("build.arch", fqbn.architecture.to_uppercase());
+    Ok(Board { fqbn, properties })
+  }
+
+  /// Platform and board properties, for expanding `platform.txt` recipes
+  pub(crate) fn properties(&self) -> &Properties {
+    &self.properties
+  }
+
+  fn build_property(&self, key: &str) -> Option<&str> {
+    self.properties.get(&format!("build.{}", key))
+  }
+
+  /// `build.mcu`, e.g. `atmega328p`
+  pub(crate) fn mcu(&self) -> Option<&str> {
+    self.build_property("mcu")
+  }
+
+  /// `build.variant`, the directory under the core's `variants`
+  pub(crate) fn variant(&self) -> Option<&str> {
+    self.build_property("variant")
+  }
+
+  /// `build.core`, the directory under the core's `cores`
+  pub(crate) fn core(&self) -> Option<&str> {
+    self.build_property("core")
+  }
+
+  /// `ARDUINO=<runtime.ide.version>`, `F_CPU`, `ARDUINO_<build.board>` and
+  /// `ARDUINO_ARCH_<ARCHITECTURE>`
+  pub(crate) fn definitions(&self) -> Vec<(String, String)> {
+    let ide_version = self
+      .properties
+      .get("runtime.ide.version")
+      .unwrap_or(DEFAULT_IDE_VERSION);
+    let mut definitions = vec![
+      ("ARDUINO".to_string(), ide_version.to_string()),
+      (
+        format!("ARDUINO_ARCH_{}", self.fqbn.architecture.to_uppercase()),
+        "1".to_string(),
+      ),
+    ];
+    if let Some(f_cpu) = self.build_property("f_cpu") {
+      definitions.push(("F_CPU".to_string(), f_cpu.to_string()));
+    }
+    if let Some(board) = self.build_property("board") {
+      definitions.push((format!("ARDUINO_{}", board), "1".to_string()));
+    }
+    definitions
+  }
+
+  /// `-mmcu=<build.mcu>` followed by the expanded `build.extra_flags`
+  pub(crate) fn flags(&self) -> Result<Vec<String>, ConfigError> {
+    let mut flags: Vec<String> = self
+      .mcu()
+      .map(|mcu| format!("-mmcu={}", mcu))
+      .into_iter()
+      .collect();
+    if let Some(extra_flags) = self.build_property("extra_flags") {
+      let extra_flags = self.properties.expand(extra_flags);
+      flags.extend(shlex::split(&extra_flags).ok_or(ConfigError::UnbalancedQuotes(extra_flags))?);
+    }
+    Ok(flags)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const BOARDS_TXT: &str = "\
+uno.name=Arduino Uno
+uno.build.mcu=atmega328p
+uno.build.f_cpu=16000000L
+uno.build.board=AVR_UNO
+uno.build.core=arduino
+uno.build.variant=standard
+uno.build.extra_flags=
+
+leonardo.name=Arduino Leonardo
+leonardo.build.mcu=atmega32u4
+leonardo.build.vid=0x2341
+leonardo.build.pid=0x8036
+leonardo.build.usb_product=\"Arduino Leonardo\"
+leonardo.build.usb_flags=-DUSB_VID={build.vid} -DUSB_PID={build.pid} '-DUSB_MANUFACTURER={build.usb_manufacturer}' '-DUSB_PRODUCT={build.usb_product}'
+leonardo.build.extra_flags={build.usb_flags}
+
+nano.name=Arduino Nano
+nano.build.f_cpu=16000000L
+nano.build.board=AVR_NANO
+nano.build.variant=eightanaloginputs
+nano.menu.cpu.atmega328=ATmega328P
+nano.menu.cpu.atmega328.upload.speed=115200
+nano.menu.cpu.atmega328.build.mcu=atmega328p
+nano.menu.cpu.atmega328old=ATmega328P (Old Bootloader)
+nano.menu.cpu.atmega328old.upload.speed=57600
+nano.menu.cpu.atmega328old.build.mcu=atmega328p
+nano.menu.cpu.atmega168=ATmega168
+nano.menu.cpu.atmega168.upload.speed=19200
+nano.menu.cpu.atmega168.build.mcu=atmega168
+";
+
+  fn load(fqbn: &str) -> Result<Board, ConfigError> {
+    let root = tempfile::tempdir().unwrap();
+    let boards_txt = root.path().join("boards.txt");
+    std::fs::write(&boards_txt, BOARDS_TXT).unwrap();
+    let platform = Properties::parse("build.usb_manufacturer=\"Unknown\"\n");
+    Board::load(&boards_txt, fqbn.parse().unwrap(), platform)
+  }
+
+  #[test]
+  fn parses_fqbn() {
+    assert_eq!(
+      "arduino:avr:uno".parse::<Fqbn>().unwrap(),
+      Fqbn {
+        vendor: "arduino".into(),
+        architecture: "avr".into(),
+        board: "uno".into(),
+        options: BTreeMap::new(),
+      }
+    );
+    assert_eq!(
+      "arduino:avr:pro:cpu=8MHzatmega328"
+        .parse::<Fqbn>()
+        .unwrap()
+        .options,
+      BTreeMap::from([("cpu".to_string(), "8MHzatmega328".to_string())])
+    );
+    assert!("arduino:avr:pro:cpu".parse::<Fqbn>().is_err());
+    assert!("arduino:uno".parse::<Fqbn>().is_err());
+    assert!("arduino::uno".parse::<Fqbn>().is_err());
+  }
+
+  #[test]
+  fn resolves_board_settings() {
+    let board = load("arduino:avr:uno").unwrap();
+    assert_eq!(board.variant(), Some("standard"));
+    assert_eq!(board.core(), Some("arduino"));
+    assert_eq!(board.flags().unwrap(), ["-mmcu=atmega328p"]);
+    assert_eq!(
+      board.definitions(),
+      [
+        ("ARDUINO".to_string(), "10607".to_string()),
+        ("ARDUINO_ARCH_AVR".to_string(), "1".to_string()),
+        ("F_CPU".to_string(), "16000000L".to_string()),
+        ("ARDUINO_AVR_UNO".to_string(), "1".to_string()),
+      ]
+    );
+    assert!(matches!(
+      load("arduino:avr:mega"),
+      Err(ConfigError::UnknownBoard(..))
+    ));
+  }
+
+  #[test]
+  fn expands_extra_flags() {
+    let board = load("arduino:avr:leonardo").unwrap();
+    assert_eq!(
+      board.flags().unwrap(),
+      [
+        "-mmcu=atmega32u4",
+        "-DUSB_VID=0x2341",
+        "-DUSB_PID=0x8036",
+        "-DUSB_MANUFACTURER=\"Unknown\"",
+        "-DUSB_PRODUCT=\"Arduino Leonardo\"",
+      ]
+    );
+  }
+
+  #[test]
+  fn applies_menu_overrides() {
+    let board = load("arduino:avr:nano:cpu=atmega168").unwrap();
+    assert_eq!(board.mcu(), Some("atmega168"));
+    assert_eq!(board.properties.get("upload.speed"), Some("19200"));
+  }
+
+  #[test]
+  fn reports_valid_choices() {
+    match load("arduino:avr:nano") {
+      Err(ConfigError::MissingBoardOption(board, menu, choices)) => {
+        assert_eq!((board.as_str(), menu.as_str()), ("nano", "cpu"));
+        assert_eq!(choices, ["atmega168", "atmega328", "atmega328old"]);
+      }
+      other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(
+      load("arduino:avr:nano:cpu=atmega2560"),
+      Err(ConfigError::InvalidBoardOption(..))
+    ));
+    assert!(matches!(
+      load("arduino:avr:uno:cpu=atmega328"),
+      Err(ConfigError::UnknownBoardMenu(..))
+    ));
+  }
+}
@@ -0,0 +1,152 @@
+use crate::discover::includes;
+use crate::ConfigError;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever the key layout changes, so old entries are never hit
+const KEY_VERSION: &str = "rarduino-object-1";
+
+/// `$XDG_CACHE_HOME/rarduino`, `$HOME/.cache/rarduino`, or `%LOCALAPPDATA%\rarduino` on windows
+pub(crate) fn shared_dir() -> Option<PathBuf> {
+  if cfg!(windows) {
+    env::var_os("LOCALAPPDATA").map(|dir| PathBuf::from(dir).join("rarduino"))
+  } else {
+    env::var_os("XDG_CACHE_HOME")
+      .map(PathBuf::from)
+      .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+      .map(|dir| dir.join("rarduino"))
+  }
+}
+
+/// Compiled objects, stored under a hash of everything that goes into them
+pub(crate) struct Cache {
+  dir: PathBuf,
+  /// Header hashes, headers are usually included by many sources
+  headers: HashMap<PathBuf, String>,
+}
+
+impl Cache {
+  pub(crate) fn open(dir: PathBuf) -> Result<Self, ConfigError> {
+    fs::create_dir_all(&dir)?;
+    Ok(Cache {
+      dir,
+      headers: HashMap::new(),
+    })
+  }
+
+  fn hash_file(&mut self, path: &Path) -> Result<String, ConfigError> {
+    if let Some(hash) = self.headers.get(path) {
+      return Ok(hash.clone());
+    }
+    let hash = format!("{:x}", Sha256::digest(fs::read(path)?));
+    self.headers.insert(path.to_path_buf(), hash.clone());
+    Ok(hash)
+  }
+
+  /// Key of the object `source` compiles to with `command`, the compiler, its version and
+  /// every argument, covering the headers it includes through `include_dirs`, transitively
+  ///
+  /// Includes are found without evaluating the preprocessor, so headers behind a false
+  /// `#if` count too, and headers outside `include_dirs` are left to the compiler version
+  pub(crate) fn key(
+    &mut self,
+    command: &[String],
+    include_dirs: &[PathBuf],
+    source: &Path,
+  ) -> Result<String, ConfigError> {
+    let mut headers = BTreeSet::new();
+    let mut pending = vec![source.to_path_buf()];
+    while let Some(file) = pending.pop() {
+      for header in includes(&String::from_utf8_lossy(&fs::read(&file)?)) {
+        let found = file
+          .parent()
+          .into_iter()
+          .chain(include_dirs.iter().map(PathBuf::as_path))
+          .map(|dir| dir.join(&header))
+          .find(|path| path.is_file());
+        if let Some(found) = found {
+          if headers.insert(found.clone()) {
+            pending.push(found);
+          }
+        }
+      }
+    }
+    let mut hasher = Sha256::new();
+    let mut field = |value: &str| {
+      // Length prefixed so fields can't run into each other
+      hasher.update((value.len() as u64).to_le_bytes());
+      hasher.update(value);
+    };
+    field(KEY_VERSION);
+    for arg in command {
+      field(arg);
+    }
+    field(&source.to_string_lossy());
+    field(&format!("{:x}", Sha256::digest(fs::read(source)?)));
+    for header in headers {
+      let hash = self.hash_file(&header)?;
+      field(&header.to_string_lossy());
+      field(&hash);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+  }
+
+  /// The cached object for `key`, if there is one
+  pub(crate) fn get(&self, key: &str) -> Option<PathBuf> {
+    let path = self.dir.join(format!("{}.o", key));
+    path.is_file().then_some(path)
+  }
+
+  /// Copies `object` into the cache, through a temporary file so builds sharing the cache
+  /// never see half of it
+  pub(crate) fn insert(&self, key: &str, object: &Path) -> Result<PathBuf, ConfigError> {
+    let path = self.dir.join(format!("{}.o", key));
+    let temporary = self.dir.join(format!("{}.{}.tmp", key, std::process::id()));
+    fs::copy(object, &temporary)?;
+    fs::rename(&temporary, &path)?;
+    Ok(path)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keys_follow_sources_headers_and_command() {
+    let root = tempfile::tempdir().unwrap();
+    let include = root.path().join("include");
+    fs::create_dir_all(&include).unwrap();
+    let source = root.path().join("main.c");
+    fs::write(&source, "#include \"local.h\"\nint main() {}\n").unwrap();
+    fs::write(root.path().join("local.h"), "#include <shared.h>\n").unwrap();
+    fs::write(include.join("shared.h"), "#define A 1\n").unwrap();
+    fs::write(include.join("unrelated.h"), "").unwrap();
+    let command = vec!["avr-gcc".to_string(), "-Os".to_string()];
+    let include_dirs = [include.clone()];
+
+    let mut cache = Cache::open(root.path().join("cache")).unwrap();
+    let key = cache.key(&command, &include_dirs, &source).unwrap();
+    fs::write(include.join("unrelated.h"), "#define B 2\n").unwrap();
+    assert_eq!(cache.key(&command, &include_dirs, &source).unwrap(), key);
+    let other_flags = vec!["avr-gcc".to_string(), "-O2".to_string()];
+    assert_ne!(
+      cache.key(&other_flags, &include_dirs, &source).unwrap(),
+      key
+    );
+    // A fresh cache, header hashes are only remembered for one build
+    let mut cache = Cache::open(root.path().join("cache")).unwrap();
+    fs::write(include.join("shared.h"), "#define A 2\n").unwrap();
+    assert_ne!(cache.key(&command, &include_dirs, &source).unwrap(), key);
+
+    assert_eq!(cache.get(&key), None);
+    let object = root.path().join("main.o");
+    fs::write(&object, "object").unwrap();
+    let cached = cache.insert(&key, &object).unwrap();
+    assert_eq!(cache.get(&key), Some(cached.clone()));
+    assert_eq!(fs::read_to_string(cached).unwrap(), "object");
+  }
+}
@@ -0,0 +1,291 @@
+use crate::library::{installed, Library};
+use crate::versions::{parse_lenient, satisfies};
+use crate::ConfigError;
+use std::path::PathBuf;
+
+/// A `depends` entry, `Adafruit BusIO (>=1.9.0)`
+#[derive(Debug, PartialEq)]
+struct Dependency {
+  name: String,
+  constraint: Option<String>,
+}
+
+impl Dependency {
+  fn parse(entry: &str) -> Self {
+    match entry.split_once('(') {
+      Some((name, constraint)) => Dependency {
+        name: name.trim().to_string(),
+        constraint: Some(constraint.trim_end_matches(')').trim().to_string()),
+      },
+      None => Dependency {
+        name: entry.trim().to_string(),
+        constraint: None,
+      },
+    }
+  }
+
+  /// Whether `library` is the one named, libraries without library.properties are matched
+  /// by directory name, which has spaces replaced with underscores
+  fn names(&self, library: &Library) -> bool {
+    match &library.properties {
+      Some(properties) => properties.name == self.name,
+      None => library.name() == self.name.replace(' ', "_"),
+    }
+  }
+
+  fn accepts(&self, library: &Library) -> bool {
+    match (&self.constraint, &library.properties) {
+      (None, _) => true,
+      (Some(constraint), Some(properties)) => satisfies(&properties.version, constraint),
+      (Some(_), None) => false,
+    }
+  }
+}
+
+/// One line of the dependency tree reported when resolution fails
+struct Node {
+  label: String,
+  error: Option<String>,
+  children: Vec<Node>,
+}
+
+impl Node {
+  fn failed(&self) -> bool {
+    self.error.is_some() || self.children.iter().any(Node::failed)
+  }
+
+  fn render(&self, prefix: &str, out: &mut String) {
+    out.push_str(&self.label);
+    if let Some(error) = &self.error {
+      out.push_str(": ");
+      out.push_str(error);
+    }
+    out.push('\n');
+    for (i, child) in self.children.iter().enumerate() {
+      let last = i + 1 == self.children.len();
+      out.push_str(prefix);
+      out.push_str(if last { "└─ " } else { "├─ " });
+      child.render(
+        &format!("{}{}", prefix, if last { "   " } else { "│  " }),
+        out,
+      );
+    }
+  }
+}
+
+struct Resolver {
+  installed: Vec<Library>,
+  resolved: Vec<Library>,
+}
+
+impl Resolver {
+  /// Adds the dependencies of `resolved[index]`, recursively
+  fn resolve(&mut self, index: usize) -> Node {
+    let library = &self.resolved[index];
+    let mut node = Node {
+      label: match &library.properties {
+        Some(properties) if !properties.version.is_empty() => {
+          format!("{} {}", properties.name, properties.version)
+        }
+        _ => library.name(),
+      },
+      error: None,
+      children: Vec::new(),
+    };
+    let depends: Vec<Dependency> = library
+      .properties
+      .iter()
+      .flat_map(|properties| &properties.depends)
+      .map(|entry| Dependency::parse(entry))
+      .collect();
+    for dependency in depends {
+      let label = match &dependency.constraint {
+        Some(constraint) => format!("{} ({})", dependency.name, constraint),
+        None => dependency.name.clone(),
+      };
+      // Already part of the build, either listed in the config or pulled in before
+      if let Some(present) = self
+        .resolved
+        .iter()
+        .find(|library| dependency.names(library))
+      {
+        let error = (!dependency.accepts(present)).then(|| {
+          format!(
+            "{} is already used",
+            present.properties.as_ref().map_or(
+              "an unversioned copy".to_string(),
+              |properties| format!("version {}", properties.version)
+            )
+          )
+        });
+        node.children.push(Node {
+          label,
+          error,
+          children: Vec::new(),
+        });
+        continue;
+      }
+      // Highest matching version, earlier search dirs win ties
+      let candidate = self
+        .installed
+        .iter()
+        .enumerate()
+        .filter(|(_, library)| dependency.names(library) && dependency.accepts(library))
+        .max_by(|(a_index, a), (b_index, b)| {
+          let version = |library: &Library| {
+            library
+              .properties
+              .as_ref()
+              .and_then(|properties| parse_lenient(&properties.version))
+          };
+          version(a).cmp(&version(b)).then(b_index.cmp(a_index))
+        })
+        .map(|(index, _)| index);
+      match candidate {
+        Some(candidate) => {
+          self.resolved.push(self.installed.remove(candidate));
+          let mut child = self.resolve(self.resolved.len() - 1);
+          child.label = label;
+          node.children.push(child);
+        }
+        None => {
+          let found: Vec<String> = self
+            .installed
+            .iter()
+            .filter(|library| dependency.names(library))
+            .filter_map(|library| library.properties.as_ref())
+            .map(|properties| properties.version.clone())
+            .collect();
+          node.children.push(Node {
+            label,
+            error: Some(if found.is_empty() {
+              "not installed".to_string()
+            } else {
+              format!("no matching version, installed: {}", found.join(", "))
+            }),
+            children: Vec::new(),
+          });
+        }
+      }
+    }
+    node
+  }
+}
+
+/// Adds every library `libraries` transitively depends on through `depends=`, searching
+/// `search_dirs` in order of priority
+pub(crate) fn resolve(
+  libraries: Vec<Library>,
+  search_dirs: &[PathBuf],
+) -> Result<Vec<Library>, ConfigError> {
+  if libraries
+    .iter()
+    .filter_map(|library| library.properties.as_ref())
+    .all(|properties| properties.depends.is_empty())
+  {
+    return Ok(libraries);
+  }
+  let requested = libraries.len();
+  let mut resolver = Resolver {
+    installed: installed(search_dirs)?
+      .into_iter()
+      .filter(|library| {
+        !libraries
+          .iter()
+          .any(|requested| requested.dir == library.dir)
+      })
+      .collect(),
+    resolved: libraries,
+  };
+  let mut failed = false;
+  let mut tree = String::new();
+  for index in 0..requested {
+    let node = resolver.resolve(index);
+    failed |= node.failed();
+    node.render("", &mut tree);
+  }
+  if failed {
+    return Err(ConfigError::UnresolvedDependencies(tree));
+  }
+  Ok(resolver.resolved)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::path::Path;
+
+  fn install(dir: &Path, name: &str, version: &str, depends: &str) -> PathBuf {
+    let library = dir.join(name.replace(' ', "_"));
+    fs::create_dir_all(&library).unwrap();
+    fs::write(
+      library.join("library.properties"),
+      format!("name={}\nversion={}\ndepends={}\n", name, version, depends),
+    )
+    .unwrap();
+    library
+  }
+
+  #[test]
+  fn parses_dependencies() {
+    assert_eq!(
+      Dependency::parse(" Adafruit BusIO (>=1.9.0)"),
+      Dependency {
+        name: "Adafruit BusIO".into(),
+        constraint: Some(">=1.9.0".into()),
+      }
+    );
+  }
+
+  #[test]
+  fn resolves_transitively() {
+    let root = tempfile::tempdir().unwrap();
+    let user = root.path().join("user");
+    let platform = root.path().join("platform");
+    let ssd1306 = install(&user, "Adafruit SSD1306", "2.5.7", "Adafruit GFX Library");
+    install(&user, "Adafruit GFX Library", "1.11.5", "Adafruit BusIO");
+    install(&user, "Adafruit BusIO", "1.14.1", "Wire, SPI");
+    install(&platform, "Wire", "1.0", "");
+    install(&platform, "SPI", "1.0", "");
+
+    let resolved = resolve(vec![Library::open(ssd1306).unwrap()], &[user, platform]).unwrap();
+    let mut names: Vec<String> = resolved.iter().map(Library::name).collect();
+    names.sort();
+    assert_eq!(
+      names,
+      [
+        "Adafruit BusIO",
+        "Adafruit GFX Library",
+        "Adafruit SSD1306",
+        "SPI",
+        "Wire"
+      ]
+    );
+  }
+
+  #[test]
+  fn reports_tree_on_failure() {
+    let root = tempfile::tempdir().unwrap();
+    let user = root.path().join("user");
+    let ssd1306 = install(&user, "Adafruit SSD1306", "2.5.7", "Adafruit GFX Library");
+    install(
+      &user,
+      "Adafruit GFX Library",
+      "1.11.5",
+      "Adafruit BusIO (>=1.9.0), Missing",
+    );
+    install(&user, "Adafruit BusIO", "1.7.0", "");
+
+    match resolve(vec![Library::open(ssd1306).unwrap()], &[user]) {
+      Err(ConfigError::UnresolvedDependencies(tree)) => assert_eq!(
+        tree,
+        "Adafruit SSD1306 2.5.7\n\
+         └─ Adafruit GFX Library\n\
+         \x20  ├─ Adafruit BusIO (>=1.9.0): no matching version, installed: 1.7.0\n\
+         \x20  └─ Missing: not installed\n"
+      ),
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+}
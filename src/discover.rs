@@ -0,0 +1,332 @@
+use crate::exclude::Excludes;
+use crate::library::{installed, Library};
+use crate::ConfigError;
+use glob::glob;
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Extensions of the files scanned for `#include` directives
+const SCANNED_EXTENSIONS: [&str; 8] = ["c", "cpp", "cc", "cxx", "S", "h", "hpp", "hh"];
+
+/// Headers named by the `#include` directives in `source`
+///
+/// Preprocessor conditionals aren't evaluated, so headers behind an `#ifdef` are included too
+pub(crate) fn includes(source: &str) -> Vec<String> {
+  source
+    .lines()
+    .filter_map(|line| {
+      let directive = line.trim_start().strip_prefix('#')?.trim_start();
+      let header = directive.strip_prefix("include")?.trim_start();
+      let (close, header) = match header.chars().next()? {
+        '<' => ('>', &header[1..]),
+        '"' => ('"', &header[1..]),
+        _ => return None,
+      };
+      header
+        .split_once(close)
+        .map(|(header, _)| header.trim().to_string())
+    })
+    .filter(|header| !header.is_empty())
+    .collect()
+}
+
+/// Every scanned file below `root` that `excludes` doesn't match relative to `base`
+fn files(root: &Path, base: &Path, excludes: &Excludes) -> Result<Vec<PathBuf>, ConfigError> {
+  let pattern = format!(
+    "{}/**/*",
+    root
+      .to_str()
+      .ok_or(ConfigError::ConvertFailed(root.to_path_buf()))?
+  );
+  let mut files = Vec::new();
+  for file in glob(&pattern)? {
+    let file = file?;
+    if file
+      .extension()
+      .is_some_and(|ext| SCANNED_EXTENSIONS.iter().any(|scanned| ext == *scanned))
+      && !excludes.excludes(base, &file)
+    {
+      files.push(file);
+    }
+  }
+  Ok(files)
+}
+
+/// How well a library's directory name or `name` matches the header it was picked for, lower
+/// is better, following the order arduino-cli uses and ignoring case
+fn name_score(name: &str, header: &str) -> u8 {
+  let name = name.to_lowercase();
+  let header = header.to_lowercase();
+  let stem = header.strip_suffix(".h").unwrap_or(&header);
+  if name == stem {
+    0
+  } else if name == format!("{}-master", stem) {
+    1
+  } else if name.starts_with(stem) {
+    2
+  } else if name.ends_with(stem) {
+    3
+  } else if name.contains(stem) {
+    4
+  } else {
+    5
+  }
+}
+
+/// An installed library that could provide headers
+struct Candidate {
+  library: Library,
+  root: PathBuf,
+  /// Index of the search dir the library was found in
+  location: usize,
+  /// Whether the library names the architecture explicitly instead of through `*`
+  explicit_architecture: bool,
+}
+
+struct Discovery {
+  candidates: Vec<Candidate>,
+  include_dirs: Vec<PathBuf>,
+  chosen: Vec<Library>,
+  scanned: HashSet<PathBuf>,
+  queue: VecDeque<PathBuf>,
+  excludes: Excludes,
+}
+
+impl Discovery {
+  fn enqueue(&mut self, file: PathBuf) {
+    if self.scanned.insert(file.clone()) {
+      self.queue.push_back(file);
+    }
+  }
+
+  /// Makes `header` available, either from the include dirs or by choosing the library that
+  /// provides it, headers found nowhere are assumed to come with the toolchain
+  fn resolve(&mut self, header: &str, including_dir: Option<&Path>) -> Result<(), ConfigError> {
+    let found = including_dir
+      .into_iter()
+      .chain(self.include_dirs.iter().map(PathBuf::as_path))
+      .map(|dir| dir.join(header))
+      .find(|path| path.is_file());
+    if let Some(found) = found {
+      self.enqueue(found);
+      return Ok(());
+    }
+    let best = self
+      .candidates
+      .iter()
+      .enumerate()
+      .filter(|(_, candidate)| candidate.root.join(header).is_file())
+      .min_by_key(|(_, candidate)| {
+        let dir_name = candidate
+          .library
+          .dir
+          .file_name()
+          .map(|name| name.to_string_lossy().to_string())
+          .unwrap_or_default();
+        // The name counts for more than the architecture, like in arduino-cli
+        (
+          name_score(&dir_name, header).min(name_score(&candidate.library.name(), header)),
+          !candidate.explicit_architecture,
+          candidate.location,
+        )
+      })
+      .map(|(index, _)| index);
+    if let Some(best) = best {
+      let candidate = self.candidates.remove(best);
+      self.choose(candidate.library, candidate.root)?;
+    }
+    Ok(())
+  }
+
+  /// Adds `library` to the build and queues all its files for scanning
+  fn choose(&mut self, library: Library, root: PathBuf) -> Result<(), ConfigError> {
+    let excludes = self.excludes.with_ignore_file(&library.dir)?;
+    for file in files(&root, &library.dir, &excludes)? {
+      self.enqueue(file);
+    }
+    self.include_dirs.push(root);
+    self.chosen.push(library);
+    Ok(())
+  }
+}
+
+/// Adds the libraries providing the headers `sources` and `headers` include, transitively,
+/// like arduino-builder does for sketches
+///
+/// `include_dirs` are the core and variant dirs, `libraries` the ones already in the build and
+/// `search_dirs` where the others are looked up, in order of priority
+pub(crate) fn discover(
+  sources: &[PathBuf],
+  headers: &[String],
+  include_dirs: &[PathBuf],
+  libraries: Vec<Library>,
+  search_dirs: &[PathBuf],
+  architecture: &str,
+  excludes: &Excludes,
+) -> Result<Vec<Library>, ConfigError> {
+  let candidates = installed(search_dirs)?
+    .into_iter()
+    .filter(|library| !libraries.iter().any(|chosen| chosen.dir == library.dir))
+    .filter(|library| library.validate(architecture).is_ok())
+    .map(|library| {
+      let root = library.src_root();
+      let location = search_dirs
+        .iter()
+        .position(|dir| library.dir.starts_with(dir))
+        .unwrap_or(search_dirs.len());
+      let explicit_architecture = library.properties.as_ref().is_some_and(|properties| {
+        properties
+          .architectures
+          .iter()
+          .any(|supported| supported.eq_ignore_ascii_case(architecture))
+      });
+      Candidate {
+        library,
+        root,
+        location,
+        explicit_architecture,
+      }
+    })
+    .collect();
+  let mut discovery = Discovery {
+    candidates,
+    include_dirs: include_dirs.to_vec(),
+    chosen: Vec::new(),
+    scanned: HashSet::new(),
+    queue: VecDeque::new(),
+    excludes: excludes.clone(),
+  };
+  for library in libraries {
+    let root = library.src_root();
+    discovery.choose(library, root)?;
+  }
+  for source in sources {
+    discovery.enqueue(source.clone());
+  }
+  for header in headers {
+    discovery.resolve(header, None)?;
+  }
+  while let Some(file) = discovery.queue.pop_front() {
+    for header in includes(&String::from_utf8_lossy(&fs::read(&file)?)) {
+      discovery.resolve(&header, file.parent())?;
+    }
+  }
+  Ok(discovery.chosen)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn write(path: &Path, contents: &str) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+  }
+
+  #[test]
+  fn parses_include_directives() {
+    assert_eq!(
+      includes(
+        "#include <Servo.h>\n\
+         \x20 #  include \"utility/twi.h\" // comment\n\
+         #ifdef FOO\n\
+         #include<SPI.h>\n\
+         #endif\n\
+         #define include <nope.h>\n"
+      ),
+      ["Servo.h", "utility/twi.h", "SPI.h"]
+    );
+  }
+
+  #[test]
+  fn scores_names_like_arduino() {
+    assert_eq!(name_score("Servo", "Servo.h"), 0);
+    assert_eq!(name_score("Servo-master", "Servo.h"), 1);
+    assert!(name_score("ServoEasing", "Servo.h") < name_score("MyServo", "Servo.h"));
+    assert_eq!(name_score("Other", "Servo.h"), 5);
+    assert_eq!(name_score("servo", "Servo.h"), 0);
+  }
+
+  #[test]
+  fn discovers_libraries_transitively() {
+    let root = tempfile::tempdir().unwrap();
+    let user = root.path().join("user");
+    let platform = root.path().join("platform");
+    let core = root.path().join("core");
+    write(&core.join("Arduino.h"), "#include <avr/io.h>\n");
+    write(
+      &root.path().join("sketch.cpp"),
+      "#include <Arduino.h>\n#include <Display.h>\n",
+    );
+    write(&user.join("Display/src/Display.h"), "#include <Wire.h>\n");
+    write(&user.join("Display/library.properties"), "name=Display\n");
+    write(
+      &user.join("Display/src/Display.cpp"),
+      "#include \"Display.h\"\n",
+    );
+    // Provides Display.h too, but its name doesn't match as well
+    write(&user.join("OtherDisplay/Display.h"), "");
+    write(&platform.join("Wire/src/Wire.h"), "");
+    write(
+      &platform.join("Wire/library.properties"),
+      "name=Wire\narchitectures=avr\n",
+    );
+    write(&user.join("Wire/Wire.h"), "");
+    write(&platform.join("Unused/Unused.h"), "");
+
+    let chosen = discover(
+      &[root.path().join("sketch.cpp")],
+      &[],
+      &[core],
+      Vec::new(),
+      &[user.clone(), platform.clone()],
+      "avr",
+      &Excludes::default(),
+    )
+    .unwrap();
+    let dirs: Vec<&Path> = chosen.iter().map(|library| library.dir.as_path()).collect();
+    // Wire names avr explicitly, which beats the user copy's location
+    assert_eq!(dirs, [user.join("Display"), platform.join("Wire")]);
+  }
+
+  #[test]
+  fn prefers_name_over_architecture() {
+    let root = tempfile::tempdir().unwrap();
+    let user = root.path().join("user");
+    write(
+      &root.path().join("sketch.cpp"),
+      "#include <Servo.h>\n#include <Motor.h>\n",
+    );
+    write(&user.join("Servo/src/Servo.h"), "");
+    write(
+      &user.join("Servo/library.properties"),
+      "name=Servo\narchitectures=*\n",
+    );
+    write(&user.join("ServoEasing/src/Servo.h"), "");
+    write(
+      &user.join("ServoEasing/library.properties"),
+      "name=ServoEasing\narchitectures=avr\n",
+    );
+    // Matched by its name rather than its directory
+    write(&user.join("motor_driver/Motor.h"), "");
+    write(
+      &user.join("motor_driver/library.properties"),
+      "name=motor\n",
+    );
+    write(&user.join("MotorShield/Motor.h"), "");
+
+    let chosen = discover(
+      &[root.path().join("sketch.cpp")],
+      &[],
+      &[],
+      Vec::new(),
+      std::slice::from_ref(&user),
+      "avr",
+      &Excludes::default(),
+    )
+    .unwrap();
+    let dirs: Vec<&Path> = chosen.iter().map(|library| library.dir.as_path()).collect();
+    assert_eq!(dirs, [user.join("Servo"), user.join("motor_driver")]);
+  }
+}
@@ -0,0 +1,134 @@
+use crate::ConfigError;
+use glob::Pattern;
+use std::fs;
+use std::path::{Component, Path};
+
+/// Top level directories of a library that hold sketches, tooling or tests rather than its
+/// sources
+const SKIPPED_DIRS: [&str; 4] = ["examples", "extras", "test", "tests"];
+/// File in a library's directory listing more exclude globs, one per line
+const IGNORE_FILE: &str = ".rarduinoignore";
+
+fn patterns(patterns: &[String]) -> Result<Vec<Pattern>, ConfigError> {
+  Ok(
+    patterns
+      .iter()
+      .map(|pattern| Pattern::new(pattern))
+      .collect::<Result<_, _>>()?,
+  )
+}
+
+/// Decides which files below a library or the core are left out of the build
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Excludes {
+  exclude: Vec<Pattern>,
+  /// When not empty, only the files matching one of these are kept
+  include_only: Vec<Pattern>,
+}
+
+impl Excludes {
+  pub(crate) fn new(exclude: &[String]) -> Result<Self, ConfigError> {
+    Ok(Excludes {
+      exclude: patterns(exclude)?,
+      include_only: Vec::new(),
+    })
+  }
+
+  /// These excludes plus `exclude`, keeping only the files `include_only` matches if it isn't
+  /// empty
+  pub(crate) fn with_patterns(
+    &self,
+    exclude: &[String],
+    include_only: &[String],
+  ) -> Result<Self, ConfigError> {
+    let mut excludes = self.clone();
+    excludes.exclude.extend(patterns(exclude)?);
+    excludes.include_only.extend(patterns(include_only)?);
+    Ok(excludes)
+  }
+
+  /// These excludes plus the ones in `dir/.rarduinoignore`, `#` starts a comment
+  pub(crate) fn with_ignore_file(&self, dir: &Path) -> Result<Self, ConfigError> {
+    let mut excludes = self.clone();
+    let file = dir.join(IGNORE_FILE);
+    if file.is_file() {
+      for line in fs::read_to_string(file)?.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if !line.is_empty() {
+          excludes.exclude.push(Pattern::new(line)?);
+        }
+      }
+    }
+    Ok(excludes)
+  }
+
+  /// Whether `path` is left out, it's matched relative to `base`
+  ///
+  /// Files in a hidden directory or one of [`SKIPPED_DIRS`] right below `base` are always left
+  /// out, deeper ones are sources like any other, as `src` is compiled in full
+  pub(crate) fn excludes(&self, base: &Path, path: &Path) -> bool {
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    let skipped_dir = relative
+      .parent()
+      .and_then(|parent| parent.components().next())
+      .is_some_and(|component| match component {
+        Component::Normal(name) => {
+          let name = name.to_string_lossy();
+          name.starts_with('.') || SKIPPED_DIRS.contains(&name.as_ref())
+        }
+        _ => false,
+      });
+    let matches = |patterns: &[Pattern]| {
+      patterns
+        .iter()
+        .any(|pattern| pattern.matches_path(relative))
+    };
+    skipped_dir
+      || matches(&self.exclude)
+      || (!self.include_only.is_empty() && !matches(&self.include_only))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn skips_layout_dirs_and_globs() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(
+      root.path().join(IGNORE_FILE),
+      "# generated for the host\nsrc/host/*\n",
+    )
+    .unwrap();
+    let excludes = Excludes::new(&["*_test.cpp".into()])
+      .unwrap()
+      .with_ignore_file(root.path())
+      .unwrap();
+    let base = Path::new("/libraries/.hidden");
+    for (path, excluded) in [
+      ("src/Servo.cpp", false),
+      ("examples/Sweep/Sweep.cpp", true),
+      ("extras/tool.c", true),
+      ("src/extras/tool.c", false),
+      (".git/hook.c", true),
+      ("src/servo_test.cpp", true),
+      ("src/host/mock.c", true),
+      ("src/hosted.c", false),
+    ] {
+      assert_eq!(
+        excludes.excludes(base, &base.join(path)),
+        excluded,
+        "{}",
+        path
+      );
+    }
+
+    let only = excludes
+      .with_patterns(&["src/broken.c".into()], &["src/*.c".into()])
+      .unwrap();
+    assert!(!only.excludes(base, &base.join("src/hosted.c")));
+    assert!(only.excludes(base, &base.join("src/broken.c")));
+    assert!(only.excludes(base, &base.join("src/Servo.cpp")));
+  }
+}
@@ -1,11 +1,40 @@
-use core::error;
+mod board;
+mod cache;
+mod dependencies;
+mod discover;
+mod exclude;
+mod library;
+mod locate;
+mod package_index;
+mod platform;
+mod profile;
+mod properties;
+mod size;
+mod sketch;
+mod versions;
+
+use board::{Board, Fqbn};
+use cache::Cache;
+use exclude::Excludes;
 use glob::glob;
+use library::{Layout, Library};
+pub use library::{LibraryProperties, Precompiled};
+use locate::Candidates;
+use platform::PlatformFlags;
+use profile::Profile;
+use properties::Properties;
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::fs::DirEntry;
-use std::path::PathBuf;
+use size::TrialLink;
+use sketch::Sketch;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::{fs, io};
+use versions::select_version;
 
+/// Regexes passed to bindgen, see [`bindgen::Builder::allowlist_function`] and friends
 #[derive(Debug, Deserialize)]
 pub struct BindgenLists {
   pub allowlist_function: Vec<String>,
@@ -14,40 +43,305 @@ pub struct BindgenLists {
   pub blocklist_type: Vec<String>,
 }
 
+/// Where to look for `#include` directives when discovering libraries
+#[derive(Debug, Default, Deserialize)]
+pub struct Discover {
+  /// Globs of C/C++ sources, relative to the crate being built
+  /// Usually:
+  /// 'csrc/**/*.cpp'
+  #[serde(default)]
+  pub sources: Vec<String>,
+  /// Headers to make available, as they would be included
+  /// Usually:
+  /// 'Servo.h'
+  #[serde(default)]
+  pub headers: Vec<String>,
+}
+
+/// A library to use, either by directory name or with settings for its sources only
+///
+/// Usually:
+/// Wire
+/// name: RF24
+/// definitions:
+///   RF24_SPI_SPEED: '4000000'
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum LibraryEntry {
+  Name(String),
+  Configured(LibrarySettings),
+}
+
+impl LibraryEntry {
+  /// Directory name of the library
+  pub fn name(&self) -> &str {
+    match self {
+      LibraryEntry::Name(name) => name,
+      LibraryEntry::Configured(settings) => &settings.name,
+    }
+  }
+}
+
+impl From<&str> for LibraryEntry {
+  fn from(name: &str) -> Self {
+    LibraryEntry::Name(name.to_string())
+  }
+}
+
+/// Settings that only apply to the translation units of one library
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LibrarySettings {
+  /// Directory name of the library
+  pub name: String,
+  /// Definitions added to, or overriding, the global ones
+  #[serde(default)]
+  pub definitions: HashMap<String, String>,
+  /// Compile flags added after the global ones
+  #[serde(default)]
+  pub flags: Vec<String>,
+  /// Globs of sources to leave out, relative to the library's directory
+  #[serde(default)]
+  pub exclude: Vec<String>,
+  /// Globs of the only sources to compile, relative to the library's directory
+  #[serde(default)]
+  pub include_only: Vec<String>,
+}
+
+/// Overrides for one cargo profile, layered on top of the rest of the config
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProfileSettings {
+  /// gcc optimization level, without the `-O`, overriding both the one matching cargo's
+  /// opt-level and the user's flags
+  pub opt_level: Option<String>,
+  /// Whether to compile with debug info, instead of following cargo's debug setting
+  pub debug: Option<bool>,
+  /// Compiles and links with `-flto`, off by default
+  pub lto: Option<bool>,
+  /// Definitions added to, or overriding, the global ones
+  #[serde(default)]
+  pub definitions: HashMap<String, String>,
+  /// Compile flags added after the global ones
+  #[serde(default)]
+  pub flags: Vec<String>,
+  /// Flags for C files only, added after the global ones
+  #[serde(default)]
+  pub c_flags: Vec<String>,
+  /// Flags for C++ files only, added after the global ones
+  #[serde(default)]
+  pub cpp_flags: Vec<String>,
+  /// Flags for assembly files only, added after the global ones
+  #[serde(default)]
+  pub asm_flags: Vec<String>,
+  /// Linker flags added after the global ones
+  #[serde(default)]
+  pub link_flags: Vec<String>,
+}
+
+/// Opt-in mode trading a longer build for a smaller binary
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct OptimizeSize {
+  /// Also compiles and links with `-flto`
+  #[serde(default)]
+  pub lto: bool,
+}
+
+/// Where compiled objects are kept between builds
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectCache {
+  /// In the crate's `OUT_DIR`
+  #[default]
+  Project,
+  /// Under `~/.cache/rarduino`, shared by every crate
+  Shared,
+  /// Everything is recompiled on every build
+  Off,
+}
+
+/// Configuration of a rarduino build, usually loaded with [`ConfigSerialize::from_file`]
 #[derive(Debug, Deserialize)]
 pub struct ConfigSerialize {
   /// Path to the arduino home directory
   /// Usuall $HOME/.arduino15
-  pub arduino_home: PathBuf,
+  /// When left out, ARDUINO_DIRECTORIES_DATA, then directories.data from arduino-cli.yaml,
+  /// then the OS default is used
+  pub arduino_home: Option<PathBuf>,
   /// Path to the arduino external libraries directory
-  /// Usually $HOME/Arduino
-  pub external_libraries_home: PathBuf,
-  /// Core version
+  /// Usually $HOME/Arduino/libraries
+  /// When left out, the libraries directory of ARDUINO_DIRECTORIES_USER, then of
+  /// directories.user from arduino-cli.yaml, then of the OS default sketchbook is used
+  pub external_libraries_home: Option<PathBuf>,
+  /// Fully qualified board name, `vendor:architecture:board`
+  /// Usually arduino:avr:uno
+  /// Fills in the variant, definitions and flags from the core's boards.txt
+  pub board: Option<String>,
+  /// Board menu options, merged into the ones given in `board`
+  /// Usually:
+  /// cpu: atmega328old
+  #[serde(default)]
+  pub board_options: HashMap<String, String>,
+  /// Core version, or a semver requirement on it, defaults to the latest installed
   /// Usually 1.8.6
-  pub core_version: String,
-  /// Variant
+  pub core_version: Option<String>,
+  /// Variant, overrides the one from `board`
   /// Usually eightanaloginputs
-  pub variant: String,
-  /// Avr Gcc Verion
+  pub variant: Option<String>,
+  /// Avr Gcc Verion, or a semver requirement on it
+  /// Defaults to the one package_index.json pairs with the core, or the latest installed
   /// Usually 7.3.0-atmel3.6.1-arduino7
-  pub avr_gcc_version: String,
-  /// List of arduino libraries to use
-  pub arduino_libraries: Vec<String>,
-  /// List of external libraries to use
-  pub external_libraries: Vec<String>,
-  /// List of definitions
+  pub avr_gcc_version: Option<String>,
+  /// List of arduino libraries to use, by name or as a [`LibrarySettings`] table
+  /// Libraries they depend on through library.properties are added automatically
+  #[serde(default)]
+  pub arduino_libraries: Vec<LibraryEntry>,
+  /// List of external libraries to use, by name or as a [`LibrarySettings`] table
+  /// Libraries they depend on through library.properties are added automatically
+  #[serde(default)]
+  pub external_libraries: Vec<LibraryEntry>,
+  /// Directories or globs of the crate's own C, C++ and assembly sources, relative to the
+  /// crate being built
+  /// Directories are searched recursively and their top level headers are exposed to bindgen,
+  /// like the headers globs match
+  /// A header next to a `.c` file of the same name is read as C, inside `extern "C"`
+  /// Usually:
+  /// csrc
+  /// 'vendor/driver/*.c'
+  #[serde(default)]
+  pub sources: Vec<String>,
+  /// Sketch directory to compile into the same archive as the core, relative to the crate
+  /// being built
+  /// Its .ino files are preprocessed like the IDE does, its other sources and the ones in
+  /// its src directory are compiled as they are
+  /// Usually sketch/Blink
+  pub sketch: Option<PathBuf>,
+  /// Adds the libraries that provide the headers these sources and headers include,
+  /// preferring the best matching directory or library name, then the ones that name the
+  /// architecture, then external libraries over the ones bundled with the core
+  pub discover: Option<Discover>,
+  /// Globs of sources to leave out, relative to each library's directory, or to the core's
+  /// platform directory for the core
+  /// examples, extras and test directories are always left out, and libraries can list more
+  /// globs in a .rarduinoignore file
+  /// Usually:
+  /// '**/*_test.cpp'
+  #[serde(default)]
+  pub exclude: Vec<String>,
+  /// List of definitions, overriding the ones from `board`
   /// Usually:
   /// DUINO: '10807'
   /// F_CPU: 16000000L
   /// ARDUINO_AVR_UNO: '1'
   /// ARDUINO_ARCH_AVR: '1'
+  #[serde(default)]
   pub definitions: HashMap<String, String>,
-  /// List of compile flags
+  /// List of compile flags for every language, added after the ones from `board` and its
+  /// platform.txt
+  /// A `-mmcu` flag here replaces the one from `board`
   /// Usually:
   /// '-mmcu=atmega328p'
+  #[serde(default)]
   pub flags: Vec<String>,
+  /// List of flags for C files only, added after `flags`
+  /// Usually:
+  /// '-std=gnu11'
+  #[serde(default)]
+  pub c_flags: Vec<String>,
+  /// List of flags for C++ files only, added after `flags`
+  /// Usually:
+  /// '-std=gnu++17'
+  /// '-fno-threadsafe-statics'
+  #[serde(default)]
+  pub cpp_flags: Vec<String>,
+  /// List of flags for assembly files only, added after `flags`
+  /// Usually:
+  /// '-x'
+  /// 'assembler-with-cpp'
+  #[serde(default)]
+  pub asm_flags: Vec<String>,
+  /// List of linker flags, added after the ones from platform.txt and library.properties
+  /// Usually:
+  /// '-Wl,--gc-sections'
+  #[serde(default)]
+  pub link_flags: Vec<String>,
   /// List of allowed and blocked functions and types
   pub bindgen_lists: BindgenLists,
+  /// Where compiled objects are cached, keyed by a hash of their source, the headers it
+  /// includes, the compiler and its flags, so only changed sources are recompiled
+  /// project, shared or off, defaults to project
+  #[serde(default)]
+  pub object_cache: ObjectCache,
+  /// Overrides by cargo profile name, the one matching `PROFILE` applies
+  /// Usually:
+  /// release: { opt_level: 's', lto: true }
+  #[serde(default)]
+  pub profiles: HashMap<String, ProfileSettings>,
+  /// Compiles every function and variable into its own section and links with
+  /// `-Wl,--gc-sections` so unused ones are dropped, optionally with LTO, and reports the
+  /// flash saved
+  /// Usually:
+  /// { lto: true }
+  #[serde(default)]
+  pub optimize_size: Option<OptimizeSize>,
+}
+
+impl ConfigSerialize {
+  /// Deserializes the config from a `.yaml`/`.yml`, `.toml` or `.json` file
+  pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+      Some("yaml" | "yml") => Ok(serde_yaml::from_str(&contents)?),
+      Some("toml") => Ok(toml::from_str(&contents)?),
+      Some("json") => Ok(serde_json::from_str(&contents)?),
+      _ => Err(ConfigError::UnknownFormat(path.to_path_buf())),
+    }
+  }
+
+  /// Deserializes the config from the `[package.metadata.rarduino]` table of a `Cargo.toml`
+  pub fn from_manifest<P: AsRef<Path>>(manifest_path: P) -> Result<Self, ConfigError> {
+    let manifest_path = manifest_path.as_ref();
+    let manifest: toml::Table = toml::from_str(&fs::read_to_string(manifest_path)?)?;
+    let metadata = manifest
+      .get("package")
+      .and_then(|package| package.get("metadata"))
+      .and_then(|metadata| metadata.get("rarduino"))
+      .ok_or(ConfigError::NoManifestMetadata(manifest_path.to_path_buf()))?;
+    Ok(metadata.clone().try_into()?)
+  }
+}
+
+/// Loads the config at `config_path`, then compiles the arduino sources and generates bindings
+///
+/// Meant to be called from a `build.rs`:
+/// ```no_run
+/// rarduino::build("rarduino.yaml").unwrap();
+/// ```
+pub fn build<P: AsRef<Path>>(config_path: P) -> Result<(), ConfigError> {
+  println!("cargo:rerun-if-changed={}", config_path.as_ref().display());
+  build_with(ConfigSerialize::from_file(config_path)?)
+}
+
+/// Same as [`build`], but reads the config from `[package.metadata.rarduino]` in the
+/// `Cargo.toml` of the crate being built
+pub fn build_from_manifest() -> Result<(), ConfigError> {
+  let manifest_dir = env::var("CARGO_MANIFEST_DIR").map_err(ConfigError::NoManifestDir)?;
+  let manifest_path = Path::new(&manifest_dir).join("Cargo.toml");
+  println!("cargo:rerun-if-changed={}", manifest_path.display());
+  build_with(ConfigSerialize::from_manifest(manifest_path)?)
+}
+
+/// Compiles the arduino sources and generates bindings for an already loaded config
+pub fn build_with(config: ConfigSerialize) -> Result<(), ConfigError> {
+  let config = Config::try_from(config)?;
+  for path in &config.rerun_if_changed {
+    println!("cargo:rerun-if-changed={}", path.display());
+  }
+  for var in &config.rerun_if_env_changed {
+    println!("cargo:rerun-if-env-changed={}", var);
+  }
+  let profile = Profile::resolve(&|key| env::var(key).ok(), &config.profiles);
+  compile(&config, &profile)?;
+  generate_bindings(&config, &profile)
 }
 
 struct Config {
@@ -55,85 +349,113 @@ struct Config {
   includes: Vec<PathBuf>,
   /// Path to avr_gcc binary
   avr_gcc: PathBuf,
+  /// Path to the avr-gcc archiver
+  avr_ar: PathBuf,
+  /// Path to avr-size, for the size report
+  avr_size: PathBuf,
+  /// Definitions, sorted so the command line is stable
+  definitions: BTreeMap<String, String>,
+  /// List of compile flags shared by every language
+  flags: Vec<String>,
+  /// Flags for c files
+  c_flags: LanguageFlags,
+  /// Flags for cpp files
+  cpp_flags: LanguageFlags,
+  /// Flags for assembly files
+  asm_flags: LanguageFlags,
+  /// Flags for linking the final binary
+  link_flags: Vec<String>,
+  /// Archives of precompiled libraries, linked before the core
+  archives: Vec<PathBuf>,
+  /// The core and every library, each with its own sources
+  sources: Vec<SourceSet>,
+  /// Include dirs shipped with avr-gcc that clang doesn't know about
+  system_includes: Vec<PathBuf>,
+  /// Top level headers of every library and of the crate's own sources, exposed to bindgen
+  library_headers: Vec<PathBuf>,
+  /// The crate's headers for C sources, wrapped in `extern "C"` since bindgen parses C++
+  c_headers: Vec<PathBuf>,
+  /// List of allowed and blocked functions and types
+  bindgen_lists: BindgenLists,
+  /// Where compiled objects are cached
+  object_cache: ObjectCache,
+  /// Overrides by cargo profile, resolved when compiling
+  profiles: HashMap<String, ProfileSettings>,
+  /// Whether to drop unused sections, and whether with LTO
+  optimize_size: Option<OptimizeSize>,
+  /// The core's `main.cpp`, left out of the build but compiled for the size report
+  core_main: Option<PathBuf>,
+  /// Every file the build depends on, sources and headers included
+  rerun_if_changed: BTreeSet<PathBuf>,
+  /// Every environment variable read to find the files
+  rerun_if_env_changed: BTreeSet<String>,
+}
+
+/// Flags of one language, from platform.txt and from the user
+#[derive(Debug, Default)]
+struct LanguageFlags {
+  platform: Vec<String>,
+  user: Vec<String>,
+}
+
+impl LanguageFlags {
+  /// The platform's flags, then the ones matching the cargo profile, then the shared ones,
+  /// then the user's, so the most specific ones win
+  fn layer(&self, cargo: &[String], shared: &[String]) -> Vec<String> {
+    self
+      .platform
+      .iter()
+      .chain(cargo)
+      .chain(shared)
+      .chain(&self.user)
+      .cloned()
+      .collect()
+  }
+}
+
+/// Sources of the core or of one library
+struct SourceSet {
+  /// Library name, or "core"
+  name: String,
   /// List of all cpp files
   cpp_files: Vec<PathBuf>,
   /// List of all c files
   c_files: Vec<PathBuf>,
+  /// List of all assembly files
+  asm_files: Vec<PathBuf>,
+  /// Include dirs only these sources are compiled with
+  includes: Vec<PathBuf>,
+  /// Definitions only these sources are compiled with, overriding the global ones
+  definitions: BTreeMap<String, String>,
+  /// Flags only these sources are compiled with, after the global ones
+  flags: Vec<String>,
+  /// Sketch whose .ino files are preprocessed into one more cpp file when compiling
+  sketch: Option<Sketch>,
+  /// Link every object instead of only the referenced ones, like the IDE does for libraries
+  /// without dot_a_linkage
+  whole_archive: bool,
 }
 
-impl TryFrom<ConfigSerialize> for Config {
-  type Error = ConfigError;
-
-  fn try_from(value: ConfigSerialize) -> Result<Self, Self::Error> {
-    let arduino_home_str = value
-      .arduino_home
-      .to_str()
-      .ok_or(ConfigError::ArduinoHomeNoString(value.arduino_home.clone()))?;
-    let external_libraries_home_str =
-      value
-        .external_libraries_home
-        .to_str()
-        .ok_or(ConfigError::ExternalLibrariesHomeNoString(
-          value.external_libraries_home.clone(),
-        ))?;
-    let arduino_home = PathBuf::from(envmnt::expand(arduino_home_str, None)); // Location to search for Arduino libraries
-    let external_libraries_home = PathBuf::from(envmnt::expand(external_libraries_home_str, None)); // Location to search for External Libraries
-    if !arduino_home.exists() {
-      return Err(ConfigError::ArduinoHomeNoExist(arduino_home));
-    }
-    if !external_libraries_home.exists() {
-      return Err(ConfigError::ExternalLibrariesHomeNoExist(
-        external_libraries_home,
-      ));
-    }
-    //TODO: Verify assumed structure
-    let arduino_package_path = arduino_home.join("packaged").join("arduino");
-    let avr_gcc_home = arduino_package_path
-      .join("tools")
-      .join("avr-gcc")
-      .join(value.avr_gcc_version);
-    let core_path = arduino_package_path
-      .join("hardware")
-      .join("avr")
-      .join(&value.core_version);
-    let avr_gcc_bin = avr_gcc_home.join("bin").join("avr-gcc");
-    if !avr_gcc_bin.exists() {
-      return Err(ConfigError::NoAvrGcc(avr_gcc_bin));
-    }
-
-    let arduino_includes = [
-      core_path
-        .join("hardware")
-        .join("avr")
-        .join(&value.core_version), // Path to the arduino core
-      core_path.join("variants").join(&value.variant), // Path to the arduino variant code
-      avr_gcc_home.join("include"),                    // avr-gcc includes
-    ];
-    let arduino_libraries: Vec<PathBuf> = {
-      let library_path = core_path.join("libraries");
-      value
-        .arduino_libraries
-        .iter()
-        .map(|lib| src_root(&library_path.join(lib)))
-        .collect::<Result<Vec<PathBuf>, ConfigError>>()?
-    };
-    let external_libraries: Vec<PathBuf> = value
-      .external_libraries
-      .iter()
-      .map(|lib| src_root(&external_libraries_home.join(lib)))
-      .collect::<Result<Vec<PathBuf>, ConfigError>>()?;
-    let mut include_dirs = Vec::from(arduino_includes);
-    include_dirs.extend(arduino_libraries);
-    include_dirs.extend(external_libraries);
-
+impl SourceSet {
+  /// Collects the c, cpp and assembly files in `roots`, and in their subdirectories if
+  /// `recursive`, leaving out the ones `excludes` matches relative to `base`
+  fn collect(
+    name: String,
+    roots: &[PathBuf],
+    recursive: bool,
+    base: &Path,
+    excludes: &Excludes,
+    whole_archive: bool,
+  ) -> Result<Self, ConfigError> {
     let get_type = |pattern: &str| -> Result<Vec<PathBuf>, ConfigError> {
       let mut result = Vec::new();
-      for file in &include_dirs {
+      for file in roots {
         let files = glob(&format!(
-          "{}/**/{}",
+          "{}/{}{}",
           file
             .to_str()
             .ok_or(ConfigError::ConvertFailed(file.clone()))?,
+          if recursive { "**/" } else { "" },
           pattern
         ))?
         .filter_map(|f| -> Option<Result<PathBuf, ConfigError>> {
@@ -141,56 +463,950 @@ impl TryFrom<ConfigSerialize> for Config {
             Ok(path) => path,
             Err(e) => return Some(Err(e.into())),
           };
-          if path.ends_with("main.cpp") {
+          if path.ends_with("main.cpp") || excludes.excludes(base, &path) {
             None
           } else {
             Some(Ok(path))
           }
-        }).collect::<Result<Vec<PathBuf>, ConfigError>>()?;
+        })
+        .collect::<Result<Vec<PathBuf>, ConfigError>>()?;
         result.extend(files);
       }
       Ok(result)
     };
-    todo!()
+    let get_types = |patterns: &[&str]| -> Result<Vec<PathBuf>, ConfigError> {
+      let mut result = Vec::new();
+      for pattern in patterns {
+        result.extend(get_type(pattern)?);
+      }
+      result.sort();
+      result.dedup();
+      Ok(result)
+    };
+    Ok(SourceSet {
+      name,
+      c_files: get_types(&["*.c"])?,
+      cpp_files: get_types(&["*.cpp", "*.cc", "*.cxx"])?,
+      asm_files: get_types(&["*.S"])?,
+      includes: Vec::new(),
+      definitions: BTreeMap::new(),
+      flags: Vec::new(),
+      sketch: None,
+      whole_archive,
+    })
+  }
+
+  /// Sorts `files` by language, leaving out the ones that aren't sources
+  fn from_files(name: String, files: Vec<PathBuf>, whole_archive: bool) -> Self {
+    let mut set = SourceSet {
+      name,
+      cpp_files: Vec::new(),
+      c_files: Vec::new(),
+      asm_files: Vec::new(),
+      includes: Vec::new(),
+      definitions: BTreeMap::new(),
+      flags: Vec::new(),
+      sketch: None,
+      whole_archive,
+    };
+    for file in files {
+      match file.extension().and_then(|ext| ext.to_str()) {
+        Some("c") => set.c_files.push(file),
+        Some("cpp" | "cc" | "cxx") => set.cpp_files.push(file),
+        Some("S") => set.asm_files.push(file),
+        _ => {}
+      }
+    }
+    for files in [&mut set.c_files, &mut set.cpp_files, &mut set.asm_files] {
+      files.sort();
+      files.dedup();
+    }
+    set
   }
 }
 
-fn src_root(loc: &PathBuf) -> Result<PathBuf, ConfigError> {
-  let children: Vec<PathBuf> = fs::read_dir(loc)?
-    .collect::<io::Result<Vec<DirEntry>>>()?
-    .into_iter()
-    .map(|x| x.path())
-    .collect();
-  let src_path = loc.join("./src");
-  let utility_path = loc.join("./utility");
-  let src = children.contains(&src_path);
-  let utility = children.contains(&utility_path);
-  match (src, utility) {
-    (true, true) => Err(ConfigError::MalformedLib(loc.clone())),
-    (true, false) => Ok(src_path),
-    (false, true) => Ok(utility_path),
-    (false, false) => Ok(loc.clone()),
+impl TryFrom<ConfigSerialize> for Config {
+  type Error = ConfigError;
+
+  fn try_from(value: ConfigSerialize) -> Result<Self, Self::Error> {
+    let env_vars = RefCell::new(BTreeSet::new());
+    let expand =
+      |path: &Path, on_error: fn(PathBuf) -> ConfigError| -> Result<PathBuf, ConfigError> {
+        let path_str = path.to_str().ok_or_else(|| on_error(path.to_path_buf()))?;
+        env_vars.borrow_mut().extend(referenced_env_vars(path_str));
+        Ok(PathBuf::from(envmnt::expand(path_str, None)))
+      };
+    // arduino-cli's config is only read when it's needed, a broken one can't fail other builds
+    let mut rerun_if_changed = BTreeSet::new();
+    let candidates = if value.arduino_home.is_none() || value.external_libraries_home.is_none() {
+      Candidates::new(&|key| {
+        env_vars.borrow_mut().insert(key.to_string());
+        env::var(key).ok()
+      })?
+    } else {
+      Candidates::default()
+    };
+    rerun_if_changed.extend(candidates.cli_config.clone());
+    // Location to search for Arduino libraries
+    let arduino_home = locate::first_existing(match &value.arduino_home {
+      Some(path) => vec![expand(path, ConfigError::ArduinoHomeNoString)?],
+      None => candidates.data,
+    })
+    .map_err(ConfigError::ArduinoHomeNoExist)?;
+    // Location to search for External Libraries
+    let external_libraries_home = locate::first_existing(match &value.external_libraries_home {
+      Some(path) => vec![expand(path, ConfigError::ExternalLibrariesHomeNoString)?],
+      None => candidates
+        .user
+        .iter()
+        .map(|dir| dir.join("libraries"))
+        .collect(),
+    })
+    .map_err(ConfigError::ExternalLibrariesHomeNoExist)?;
+    let mut fqbn = value.board.as_deref().map(str::parse::<Fqbn>).transpose()?;
+    if let Some(fqbn) = &mut fqbn {
+      fqbn.options.extend(value.board_options);
+    }
+    //TODO: Verify assumed structure
+    let packages_path = arduino_home.join("packages");
+    let (vendor, architecture) = match &fqbn {
+      Some(fqbn) => (fqbn.vendor.clone(), fqbn.architecture.clone()),
+      None => ("arduino".to_string(), "avr".to_string()),
+    };
+    let core_versions = packages_path
+      .join(&vendor)
+      .join("hardware")
+      .join(&architecture);
+    let core_version = select_version(&core_versions, value.core_version.as_deref(), "core")?;
+    let core_path = core_versions.join(&core_version);
+    // Tools the installed core was released with, avr-gcc falls back to the latest installed
+    rerun_if_changed.extend(package_index::index_files(&arduino_home)?);
+    let tools =
+      package_index::tools_dependencies(&arduino_home, &vendor, &architecture, &core_version)?
+        .unwrap_or_default();
+    let paired_avr_gcc = tools.iter().find(|tool| tool.name == "avr-gcc");
+    let avr_gcc_versions = packages_path
+      .join(paired_avr_gcc.map_or("arduino", |tool| tool.packager.as_str()))
+      .join("tools")
+      .join("avr-gcc");
+    let avr_gcc_home = avr_gcc_versions.join(select_version(
+      &avr_gcc_versions,
+      value
+        .avr_gcc_version
+        .as_deref()
+        .or(paired_avr_gcc.map(|tool| tool.version.as_str())),
+      "avr-gcc",
+    )?);
+    let avr_gcc_bin = avr_gcc_home.join("bin").join("avr-gcc");
+    if !avr_gcc_bin.exists() {
+      return Err(ConfigError::NoAvrGcc(avr_gcc_bin));
+    }
+    let avr_ar_bin = avr_gcc_home.join("bin").join("avr-gcc-ar");
+    let avr_size_bin = avr_gcc_home.join("bin").join("avr-size");
+    let board = fqbn
+      .map(|fqbn| -> Result<Board, ConfigError> {
+        let platform_txt = core_path.join("platform.txt");
+        rerun_if_changed.insert(core_path.join("boards.txt"));
+        let mut platform = if platform_txt.exists() {
+          rerun_if_changed.insert(platform_txt.clone());
+          Properties::load(&platform_txt)?
+        } else {
+          Properties::default()
+        };
+        platform.insert("runtime.platform.path", core_path.to_string_lossy());
+        for tool in &tools {
+          let path = tool.path(&arduino_home);
+          platform.insert(
+            format!("runtime.tools.{}-{}.path", tool.name, tool.version),
+            path.to_string_lossy(),
+          );
+          platform.insert(
+            format!("runtime.tools.{}.path", tool.name),
+            path.to_string_lossy(),
+          );
+        }
+        platform.insert("runtime.tools.avr-gcc.path", avr_gcc_home.to_string_lossy());
+        Board::load(&core_path.join("boards.txt"), fqbn, platform)
+      })
+      .transpose()?;
+    let platform_flags = board
+      .as_ref()
+      .map(|board| PlatformFlags::new(board.properties()))
+      .transpose()?
+      .unwrap_or_default();
+
+    let variant = value
+      .variant
+      .as_deref()
+      .or(board.as_ref().and_then(Board::variant))
+      .ok_or(ConfigError::NoVariant)?;
+    let core = board.as_ref().and_then(Board::core).unwrap_or("arduino");
+    let arduino_sources = [
+      core_path.join("cores").join(core), // Path to the arduino core
+      core_path.join("variants").join(variant), // Path to the arduino variant code
+    ];
+    let core_main = Some(arduino_sources[0].join("main.cpp")).filter(|main| main.is_file());
+    let mut definitions: BTreeMap<String, String> = board
+      .as_ref()
+      .map(Board::definitions)
+      .unwrap_or_default()
+      .into_iter()
+      .collect();
+    definitions.extend(value.definitions);
+    let overrides_mcu = value.flags.iter().any(|flag| flag.starts_with("-mmcu="));
+    let mut flags: Vec<String> = board
+      .as_ref()
+      .map(Board::flags)
+      .transpose()?
+      .unwrap_or_default()
+      .into_iter()
+      .filter(|flag| !(overrides_mcu && flag.starts_with("-mmcu=")))
+      .collect();
+    if let Some(optimize_size) = value.optimize_size {
+      flags.extend(size::compile_flags(optimize_size.lto));
+    }
+    flags.extend(value.flags);
+    let library_path = core_path.join("libraries");
+    // Settings of the configured libraries, by directory
+    let mut settings = HashMap::new();
+    let libraries = value
+      .arduino_libraries
+      .iter()
+      .map(|entry| (&library_path, entry))
+      .chain(
+        value
+          .external_libraries
+          .iter()
+          .map(|entry| (&external_libraries_home, entry)),
+      )
+      .map(|(home, entry)| {
+        let dir = home.join(entry.name());
+        if let LibraryEntry::Configured(entry_settings) = entry {
+          settings.insert(dir.clone(), entry_settings.clone());
+        }
+        Library::open(dir)
+      })
+      .collect::<Result<Vec<Library>, ConfigError>>()?;
+    // External libraries win over the ones bundled with the core, like in the IDE
+    let excludes = Excludes::new(&value.exclude)?;
+    let sketch = value
+      .sketch
+      .as_deref()
+      .map(|dir| Sketch::open(crate_relative(dir)))
+      .transpose()?;
+    let search_dirs = [external_libraries_home.clone(), library_path.clone()];
+    let libraries = match &value.discover {
+      Some(discover) => {
+        let mut sources = sketch
+          .iter()
+          .flat_map(|sketch| sketch.ino_files.iter().cloned())
+          .collect::<Vec<PathBuf>>();
+        for pattern in &discover.sources {
+          let pattern = crate_relative(Path::new(pattern));
+          let pattern = pattern
+            .to_str()
+            .ok_or(ConfigError::ConvertFailed(pattern.clone()))?;
+          for source in glob(pattern)? {
+            sources.push(source?);
+          }
+        }
+        discover::discover(
+          &sources,
+          &discover.headers,
+          &arduino_sources,
+          libraries,
+          &search_dirs,
+          &architecture,
+          &excludes,
+        )?
+      }
+      None => libraries,
+    };
+    let libraries = dependencies::resolve(libraries, &search_dirs)?;
+    let mut sources = vec![SourceSet::collect(
+      "core".to_string(),
+      &arduino_sources,
+      true,
+      &core_path,
+      &excludes,
+      false,
+    )?];
+    // Dirs whose headers are watched on top of the source dirs, they're only on the include
+    // path of their own sources
+    let mut header_dirs = Vec::new();
+    if let Some(sketch) = sketch {
+      header_dirs.push(sketch.dir.clone());
+      // The IDE links sketch objects directly, so ISR() handlers and other definitions the Rust
+      // side never references are kept
+      let mut set = SourceSet::collect(
+        "sketch".to_string(),
+        std::slice::from_ref(&sketch.dir),
+        false,
+        &sketch.dir,
+        &excludes,
+        true,
+      )?;
+      let src = SourceSet::collect(
+        "sketch".to_string(),
+        &[sketch.dir.join("src")],
+        true,
+        &sketch.dir,
+        &excludes,
+        true,
+      )?;
+      set.c_files.extend(src.c_files);
+      set.cpp_files.extend(src.cpp_files);
+      set.asm_files.extend(src.asm_files);
+      // The preprocessed sketch is compiled from OUT_DIR but includes headers next to it
+      set.includes.push(sketch.dir.clone());
+      set.sketch = Some(sketch);
+      sources.push(set);
+    }
+    let mut source_dirs = Vec::from(arduino_sources);
+    let mut library_headers = Vec::new();
+    let mut link_flags = platform_flags.elf;
+    let mut archives = Vec::new();
+    let mcu = mcu(&flags);
+    for library in &libraries {
+      library.validate(&architecture)?;
+      let root = library.src_root();
+      let includes = library
+        .properties
+        .as_ref()
+        .map(|properties| properties.includes.as_slice())
+        .unwrap_or_default();
+      if includes.is_empty() {
+        let pattern = format!(
+          "{}/*.h",
+          root
+            .to_str()
+            .ok_or(ConfigError::ConvertFailed(root.clone()))?
+        );
+        for header in glob(&pattern)? {
+          library_headers.push(header?);
+        }
+      } else {
+        library_headers.extend(includes.iter().map(|header| root.join(header)));
+      }
+      if let Some(properties) = &library.properties {
+        link_flags.extend(properties.ldflags.iter().cloned());
+      }
+      let precompiled = mcu.and_then(|mcu| library.precompiled_dir(mcu));
+      if let Some(dir) = &precompiled {
+        let pattern = format!(
+          "{}/*.a",
+          dir
+            .to_str()
+            .ok_or(ConfigError::ConvertFailed(dir.clone()))?
+        );
+        for archive in glob(&pattern)? {
+          archives.push(archive?);
+        }
+      }
+      // precompiled=full only falls back to the sources when there's no archive for the mcu
+      let full = library
+        .properties
+        .as_ref()
+        .is_some_and(|properties| properties.precompiled == Precompiled::Full);
+      if precompiled.is_some() && full {
+        source_dirs.push(root);
+        continue;
+      }
+      let library_settings = settings.remove(&library.dir).unwrap_or_default();
+      let library_excludes = excludes
+        .with_ignore_file(&library.dir)?
+        .with_patterns(&library_settings.exclude, &library_settings.include_only)?;
+      let mut set = match library.layout() {
+        Layout::Recursive(src) => SourceSet::collect(
+          library.name(),
+          &[src],
+          true,
+          &library.dir,
+          &library_excludes,
+          !library.dot_a_linkage(),
+        )?,
+        Layout::Flat { root, utility } => {
+          let mut roots = vec![root];
+          roots.extend(utility.clone());
+          let mut set = SourceSet::collect(
+            library.name(),
+            &roots,
+            false,
+            &library.dir,
+            &library_excludes,
+            !library.dot_a_linkage(),
+          )?;
+          set.includes.extend(utility);
+          set
+        }
+      };
+      set.definitions.extend(library_settings.definitions);
+      set.flags = library_settings.flags;
+      sources.push(set);
+      source_dirs.push(root);
+    }
+    let mut crate_files = Vec::new();
+    for entry in &value.sources {
+      let path = crate_relative(Path::new(entry));
+      let is_dir = path.is_dir();
+      let pattern = if is_dir {
+        // Also catches sources added to the directory
+        rerun_if_changed.insert(path.clone());
+        source_dirs.push(path.clone());
+        path.join("**").join("*")
+      } else {
+        path.clone()
+      };
+      let pattern = pattern
+        .to_str()
+        .ok_or(ConfigError::ConvertFailed(pattern.clone()))?;
+      for file in glob(pattern)? {
+        let file = file?;
+        let header = file
+          .extension()
+          .is_some_and(|ext| ext == "h" || ext == "hpp");
+        if !header {
+          if let Some(dir) = file.parent().filter(|_| !is_dir) {
+            header_dirs.push(dir.to_path_buf());
+          }
+          crate_files.push(file);
+        } else if !is_dir || file.parent() == Some(path.as_path()) {
+          let dir = file.parent().map(Path::to_path_buf).unwrap_or_default();
+          if !source_dirs.contains(&dir) {
+            source_dirs.push(dir);
+          }
+          library_headers.push(file);
+        }
+      }
+    }
+    let c_headers = library_headers
+      .iter()
+      .filter(|header| {
+        header.extension().is_some_and(|ext| ext == "h")
+          && crate_files.contains(&header.with_extension("c"))
+      })
+      .cloned()
+      .collect();
+    if !crate_files.is_empty() {
+      // The crate asked for these sources by name, so none of them is dropped for lack of a
+      // reference, interrupt vectors and constructors included
+      sources.push(SourceSet::from_files(
+        "sources".to_string(),
+        crate_files,
+        true,
+      ));
+    }
+
+    for library in &libraries {
+      for file in ["library.properties", ".rarduinoignore"] {
+        if library.dir.join(file).exists() {
+          rerun_if_changed.insert(library.dir.join(file));
+        }
+      }
+    }
+    for set in &sources {
+      rerun_if_changed.extend(
+        set
+          .c_files
+          .iter()
+          .chain(&set.cpp_files)
+          .chain(&set.asm_files)
+          .chain(set.sketch.iter().flat_map(|sketch| &sketch.ino_files))
+          .cloned(),
+      );
+    }
+    for dir in source_dirs.iter().chain(&header_dirs) {
+      let dir = dir
+        .to_str()
+        .ok_or(ConfigError::ConvertFailed(dir.clone()))?;
+      for pattern in ["h", "hpp"].map(|ext| format!("{}/**/*.{}", dir, ext)) {
+        for header in glob(&pattern)? {
+          rerun_if_changed.insert(header?);
+        }
+      }
+    }
+    rerun_if_changed.extend(archives.iter().cloned());
+
+    if let Some(optimize_size) = value.optimize_size {
+      link_flags.extend(size::link_flags(optimize_size.lto));
+      link_flags.extend(mcu.map(|mcu| format!("-mmcu={}", mcu)));
+    }
+    link_flags.extend(value.link_flags);
+
+    let mut includes = source_dirs;
+    includes.push(avr_gcc_home.join("include")); // avr-gcc includes
+    Ok(Config {
+      includes,
+      avr_gcc: avr_gcc_bin,
+      avr_ar: avr_ar_bin,
+      avr_size: avr_size_bin,
+      definitions,
+      c_flags: LanguageFlags {
+        platform: platform_flags.c,
+        user: value.c_flags,
+      },
+      cpp_flags: LanguageFlags {
+        platform: platform_flags.cpp,
+        user: value.cpp_flags,
+      },
+      asm_flags: LanguageFlags {
+        platform: platform_flags.asm,
+        user: value.asm_flags,
+      },
+      flags,
+      link_flags,
+      archives,
+      sources,
+      system_includes: vec![avr_gcc_home.join("avr").join("include")],
+      library_headers,
+      c_headers,
+      bindgen_lists: value.bindgen_lists,
+      object_cache: value.object_cache,
+      profiles: value.profiles,
+      optimize_size: value.optimize_size,
+      core_main,
+      rerun_if_changed,
+      rerun_if_env_changed: env_vars.into_inner(),
+    })
   }
 }
 
-fn compile(config: &Config) {}
+/// Name of the static archive produced by [`compile`], without the `lib` prefix
+const ARCHIVE_NAME: &str = "arduino";
+/// Name of the archive for libraries without dot_a_linkage, linked as a whole
+const WHOLE_ARCHIVE_NAME: &str = "arduino_libraries";
+
+/// Compiles the core, the variant and every library into `libarduino.a` in `OUT_DIR`
+/// and tells cargo how to link against it
+///
+/// Each language is compiled separately so it only gets its own flags. Libraries without
+/// dot_a_linkage go into `libarduino_libraries.a` instead, which is linked as a whole
+fn compile(config: &Config, profile: &Profile) -> Result<(), ConfigError> {
+  let out_dir = PathBuf::from(env::var("OUT_DIR").map_err(ConfigError::NoOutDir)?);
+  let base_build = || {
+    let mut build = cc::Build::new();
+    build
+      .compiler(&config.avr_gcc)
+      .archiver(&config.avr_ar)
+      .out_dir(&out_dir)
+      .cargo_metadata(false)
+      .pic(false)
+      .includes(&config.includes);
+    build
+  };
+  let mut cache = match config.object_cache {
+    ObjectCache::Project => Some(Cache::open(out_dir.join("object_cache"))?),
+    ObjectCache::Shared => Some(Cache::open(
+      cache::shared_dir().ok_or(ConfigError::NoCacheDir)?,
+    )?),
+    ObjectCache::Off => None,
+  };
+  let compiler_version = match cache {
+    Some(_) => {
+      let output = Command::new(&config.avr_gcc)
+        .arg("--version")
+        .output()
+        .map_err(|e| ConfigError::CompilerVersion(config.avr_gcc.clone(), e))?;
+      String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+    None => String::new(),
+  };
+  let layer = |language: &LanguageFlags| language.layer(&profile.cargo_flags, &config.flags);
+  let (c_flags, cpp_flags, asm_flags) = (
+    layer(&config.c_flags),
+    layer(&config.cpp_flags),
+    layer(&config.asm_flags),
+  );
+  let set_build = |set: &SourceSet, language_flags: &[String], profile_flags: &[String]| {
+    let mut build = base_build();
+    build.includes(&set.includes);
+    let mut definitions: BTreeMap<&String, &String> = config.definitions.iter().collect();
+    definitions.extend(&profile.definitions);
+    definitions.extend(&set.definitions);
+    for (name, value) in definitions {
+      build.define(name, value.as_str());
+    }
+    for flag in language_flags.iter().chain(profile_flags).chain(&set.flags) {
+      build.flag(flag);
+    }
+    build
+  };
+  let mut objects = Vec::new();
+  let mut whole_objects = Vec::new();
+  for set in &config.sources {
+    let mut cpp_files = set.cpp_files.clone();
+    if let Some(sketch) = &set.sketch {
+      cpp_files.push(sketch.write(&out_dir)?);
+    }
+    for (files, language_flags, profile_flags) in [
+      (&set.c_files, &c_flags, &profile.c_flags),
+      (&cpp_files, &cpp_flags, &profile.cpp_flags),
+      (&set.asm_files, &asm_flags, &profile.asm_flags),
+    ] {
+      if files.is_empty() {
+        continue;
+      }
+      let mut build = set_build(set, language_flags, profile_flags);
+      let keys = match &mut cache {
+        Some(cache) => {
+          let tool = build
+            .try_get_compiler()
+            .map_err(|e| ConfigError::CompileSources(set.name.clone(), e))?;
+          let mut command = vec![
+            compiler_version.clone(),
+            tool.path().to_string_lossy().into_owned(),
+          ];
+          command.extend(
+            tool
+              .args()
+              .iter()
+              .map(|arg| arg.to_string_lossy().into_owned()),
+          );
+          let include_dirs = [set.includes.as_slice(), config.includes.as_slice()].concat();
+          files
+            .iter()
+            .map(|file| cache.key(&command, &include_dirs, file).map(Some))
+            .collect::<Result<Vec<_>, _>>()?
+        }
+        None => vec![None; files.len()],
+      };
+      let mut compiled: Vec<Option<PathBuf>> = keys
+        .iter()
+        .map(|key| cache.as_ref()?.get(key.as_ref()?))
+        .collect();
+      let missing: Vec<usize> = (0..files.len())
+        .filter(|&index| compiled[index].is_none())
+        .collect();
+      if !missing.is_empty() {
+        let fresh = build
+          .files(missing.iter().map(|&index| &files[index]))
+          .try_compile_intermediates()
+          .map_err(|e| ConfigError::CompileSources(set.name.clone(), e))?;
+        // cc returns the objects in the order of the sources
+        for (index, object) in missing.into_iter().zip(fresh) {
+          compiled[index] = Some(match (&cache, &keys[index]) {
+            (Some(cache), Some(key)) => cache.insert(key, &object)?,
+            _ => object,
+          });
+        }
+      }
+      let compiled = compiled.into_iter().flatten();
+      if set.whole_archive {
+        whole_objects.extend(compiled);
+      } else {
+        objects.extend(compiled);
+      }
+    }
+  }
+
+  println!("cargo:rustc-link-search=native={}", out_dir.display());
+  let archive_path = |name: &str| out_dir.join(format!("lib{}.a", name));
+  let mut trial_link = TrialLink {
+    avr_gcc: &config.avr_gcc,
+    avr_size: &config.avr_size,
+    flags: mcu(&config.flags)
+      .map(|mcu| format!("-mmcu={}", mcu))
+      .into_iter()
+      .collect(),
+    objects: Vec::new(),
+    whole_archives: Vec::new(),
+    archives: config.archives.clone(),
+    out_dir: &out_dir,
+  };
+  // Libraries come first so the core resolves what they reference
+  if !whole_objects.is_empty() {
+    base_build()
+      .objects(whole_objects)
+      .try_compile(WHOLE_ARCHIVE_NAME)?;
+    trial_link
+      .whole_archives
+      .push(archive_path(WHOLE_ARCHIVE_NAME));
+    println!(
+      "cargo:rustc-link-lib=static:+whole-archive={}",
+      WHOLE_ARCHIVE_NAME
+    );
+  }
+  for archive in &config.archives {
+    let name = archive
+      .file_stem()
+      .and_then(|stem| stem.to_str())
+      .and_then(|stem| stem.strip_prefix("lib"));
+    match (archive.parent(), name) {
+      (Some(dir), Some(name)) => {
+        println!("cargo:rustc-link-search=native={}", dir.display());
+        println!("cargo:rustc-link-lib=static={}", name);
+      }
+      // Archives not named lib*.a can't be found with -l
+      _ => println!("cargo:rustc-link-arg={}", archive.display()),
+    }
+  }
+  base_build().objects(objects).try_compile(ARCHIVE_NAME)?;
+  trial_link.archives.push(archive_path(ARCHIVE_NAME));
+  println!("cargo:rustc-link-lib=static={}", ARCHIVE_NAME);
+  for flag in config.link_flags.iter().chain(&profile.link_flags) {
+    println!("cargo:rustc-link-arg={}", flag);
+  }
+  if let Some(optimize_size) = config.optimize_size {
+    let mut measure = || -> Result<(u64, u64), ConfigError> {
+      // Nothing in the archives is referenced without the core's main, which the Rust code
+      // replaces in the real link
+      if let (Some(main), Some(core)) = (&config.core_main, config.sources.first()) {
+        trial_link.objects = set_build(core, &cpp_flags, &profile.cpp_flags)
+          .file(main)
+          .try_compile_intermediates()
+          .map_err(|e| ConfigError::CompileSources(core.name.clone(), e))?;
+      }
+      Ok((
+        trial_link.flash("plain", &[])?,
+        trial_link.flash("optimized", &size::link_flags(optimize_size.lto))?,
+      ))
+    };
+    // The report is only informational, so it never fails the build
+    match measure() {
+      Ok((plain, optimized)) => println!(
+        "cargo:warning=the arduino code reached from main takes {} bytes of flash, {} less \
+         than without {}",
+        optimized,
+        plain.saturating_sub(optimized),
+        if optimize_size.lto {
+          "section garbage collection and LTO"
+        } else {
+          "section garbage collection"
+        }
+      ),
+      Err(e) => println!(
+        "cargo:warning=couldn't measure the size of the arduino code: {}",
+        e.to_string().replace('\n', " ")
+      ),
+    }
+  }
+  Ok(())
+}
+
+/// `Arduino.h` and every library header, C ones with C linkage so their bindings link against
+/// the C objects
+fn wrapper_contents(config: &Config) -> String {
+  let mut contents = String::from("#include <Arduino.h>\n");
+  for header in &config.library_headers {
+    let include = format!("#include \"{}\"\n", header.display());
+    if config.c_headers.contains(header) {
+      contents.push_str(&format!("extern \"C\" {{\n{}}}\n", include));
+    } else {
+      contents.push_str(&include);
+    }
+  }
+  contents
+}
+
+/// Generates `bindings.rs` in `OUT_DIR` for `Arduino.h` and every library header
+///
+/// The profile's definitions and preprocessor flags apply too, so the bindings see the same
+/// macros as the compiled code
+fn generate_bindings(config: &Config, profile: &Profile) -> Result<(), ConfigError> {
+  let out_dir = PathBuf::from(env::var("OUT_DIR").map_err(ConfigError::NoOutDir)?);
+  let wrapper = out_dir.join("rarduino.hpp");
+  fs::write(&wrapper, wrapper_contents(config))?;
+
+  let mut builder = bindgen::Builder::default()
+    .header(
+      wrapper
+        .to_str()
+        .ok_or(ConfigError::ConvertFailed(wrapper.clone()))?,
+    )
+    .use_core()
+    .ctypes_prefix("core::ffi")
+    .layout_tests(false)
+    .clang_args(["-x", "c++", "--target=avr"])
+    .clang_args(
+      config
+        .flags
+        .iter()
+        .filter(|flag| flag.starts_with("-mmcu=")),
+    )
+    .clang_args(
+      config
+        .definitions
+        .iter()
+        .filter(|(name, _)| !profile.definitions.contains_key(*name))
+        .chain(&profile.definitions)
+        .map(|(name, value)| format!("-D{}={}", name, value)),
+    )
+    .clang_args(profile.cpp_flags.iter().filter(|flag| {
+      ["-D", "-U", "-I"]
+        .iter()
+        .any(|prefix| flag.starts_with(prefix))
+    }))
+    .clang_args(
+      config
+        .includes
+        .iter()
+        .map(|dir| format!("-I{}", dir.display())),
+    )
+    .clang_args(
+      config
+        .system_includes
+        .iter()
+        .map(|dir| format!("-isystem{}", dir.display())),
+    );
+  let lists = &config.bindgen_lists;
+  for function in &lists.allowlist_function {
+    builder = builder.allowlist_function(function);
+  }
+  for type_ in &lists.allowlist_type {
+    builder = builder.allowlist_type(type_);
+  }
+  for function in &lists.blocklist_function {
+    builder = builder.blocklist_function(function);
+  }
+  for type_ in &lists.blocklist_type {
+    builder = builder.blocklist_type(type_);
+  }
+  builder
+    .generate()?
+    .write_to_file(out_dir.join("bindings.rs"))?;
+  Ok(())
+}
+
+/// The mcu of the last `-mmcu` in `flags`
+fn mcu(flags: &[String]) -> Option<&str> {
+  flags
+    .iter()
+    .rev()
+    .find_map(|flag| flag.strip_prefix("-mmcu="))
+}
+
+/// Resolves `path` against the crate being built, or the working directory outside a build
+/// script
+fn crate_relative(path: &Path) -> PathBuf {
+  env::var("CARGO_MANIFEST_DIR")
+    .map_or_else(|_| path.to_path_buf(), |dir| Path::new(&dir).join(path))
+}
+
+/// Names of the variables `envmnt::expand` reads in `value`, `$VAR` and `${VAR}`, or
+/// `%VAR%` on windows
+fn referenced_env_vars(value: &str) -> Vec<String> {
+  if cfg!(windows) {
+    return value
+      .split('%')
+      .skip(1)
+      .step_by(2)
+      .filter(|name| !name.is_empty())
+      .map(String::from)
+      .collect();
+  }
+  value
+    .split('$')
+    .skip(1)
+    .filter_map(|reference| {
+      let name = match reference.strip_prefix('{') {
+        Some(braced) => braced.split(['}', ':']).next()?,
+        None => reference
+          .split(['/', '\\', ':', ' ', '=', '\t', '\r', '\n'])
+          .next()?,
+      };
+      (!name.is_empty()).then(|| name.to_string())
+    })
+    .collect()
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+  paths
+    .iter()
+    .map(|path| path.to_string_lossy())
+    .collect::<Vec<_>>()
+    .join(", ")
+}
 
 #[derive(Debug, thiserror::Error)]
-enum ConfigError {
+pub enum ConfigError {
   #[error("The provided path cannot be converted to UTF-8: {}", .0.to_string_lossy())]
   ConvertFailed(PathBuf),
   #[error("The provided arduino home is not valid UTF-8: {}", .0.to_string_lossy())]
   ArduinoHomeNoString(PathBuf),
   #[error("The provided external libraries home is not valid UTF-8: {}", .0.to_string_lossy())]
   ExternalLibrariesHomeNoString(PathBuf),
-  #[error("The provided arduino home does not exist: {}", .0.to_string_lossy())]
-  ArduinoHomeNoExist(PathBuf),
-  #[error("The provided external libraries home does not exist: {}", .0.to_string_lossy())]
-  ExternalLibrariesHomeNoExist(PathBuf),
+  #[error("Couldn't find the arduino home, tried: {}", display_paths(.0))]
+  ArduinoHomeNoExist(Vec<PathBuf>),
+  #[error("Couldn't find the external libraries home, tried: {}", display_paths(.0))]
+  ExternalLibrariesHomeNoExist(Vec<PathBuf>),
+  #[error("invalid board, expected vendor:architecture:board: {0}")]
+  InvalidFqbn(String),
+  #[error("board {0} is not defined in {}", .1.to_string_lossy())]
+  UnknownBoard(String, PathBuf),
+  #[error("board {0} has no menu {1}")]
+  UnknownBoardMenu(String, String),
+  #[error("board {0} requires a {1} option, one of: {}", .2.join(", "))]
+  MissingBoardOption(String, String, Vec<String>),
+  #[error("invalid {1} option {2} for board {0}, expected one of: {}", .3.join(", "))]
+  InvalidBoardOption(String, String, String, Vec<String>),
+  #[error("unbalanced quotes in flags: {0}")]
+  UnbalancedQuotes(String),
+  #[error("no variant given and none could be derived from a board")]
+  NoVariant,
+  #[error("invalid version requirement {0}: {1}")]
+  InvalidVersionReq(String, semver::Error),
+  #[error(
+    "no {what} version matching {requested} in {}, installed: {}",
+    .dir.to_string_lossy(),
+    .installed.join(", ")
+  )]
+  NoMatchingVersion {
+    what: &'static str,
+    requested: String,
+    dir: PathBuf,
+    installed: Vec<String>,
+  },
+  #[error("failed to parse package index {}: {1}", .0.to_string_lossy())]
+  PackageIndex(PathBuf, serde_json::Error),
   #[error("Couldn't find avr-gcc at {}", .0.to_string_lossy())]
   NoAvrGcc(PathBuf),
-  #[error("malformed library, expected one of 'utility', 'src', or neither: {}", .0.to_string_lossy())]
-  MalformedLib(PathBuf),
+  #[error("OUT_DIR is not set, compile must be run from a build script: {0}")]
+  NoOutDir(env::VarError),
+  #[error("failed to compile the sources of {0}: {1}")]
+  CompileSources(String, cc::Error),
+  #[error("failed to run {} --version: {1}", .0.to_string_lossy())]
+  CompilerVersion(PathBuf, io::Error),
+  #[error("no cache directory, neither XDG_CACHE_HOME nor HOME is set")]
+  NoCacheDir,
+  #[error("failed to run {}: {1}", .0.to_string_lossy())]
+  RunTool(PathBuf, io::Error),
+  #[error("failed to link the arduino code to measure its size:\n{0}")]
+  TrialLink(String),
+  #[error("unexpected avr-size output: {0}")]
+  SizeOutput(String),
+  #[error("failed to archive the arduino sources: {0}")]
+  Compile(#[from] cc::Error),
+  #[error("failed to generate bindings: {0}")]
+  Bindgen(#[from] bindgen::BindgenError),
+  #[error("unknown config format, expected .yaml, .yml, .toml or .json: {}", .0.to_string_lossy())]
+  UnknownFormat(PathBuf),
+  #[error("CARGO_MANIFEST_DIR is not set, the manifest must be read from a build script: {0}")]
+  NoManifestDir(env::VarError),
+  #[error("no [package.metadata.rarduino] table in {}", .0.to_string_lossy())]
+  NoManifestMetadata(PathBuf),
+  #[error("failed to parse yaml config: {0}")]
+  Yaml(#[from] serde_yaml::Error),
+  #[error("failed to parse toml config: {0}")]
+  Toml(#[from] toml::de::Error),
+  #[error("failed to parse json config: {0}")]
+  Json(#[from] serde_json::Error),
+  #[error("no sketch in {}, expected a .ino file named after the directory", .0.to_string_lossy())]
+  NoSketch(PathBuf),
+  #[error("library not found: {}", .0.to_string_lossy())]
+  NoLibrary(PathBuf),
+  #[error("library.properties has no name: {}", .0.to_string_lossy())]
+  LibraryNoName(PathBuf),
+  #[error(
+    "library {library} does not support {architecture}, only: {}",
+    .supported.join(", ")
+  )]
+  UnsupportedArchitecture {
+    library: String,
+    architecture: String,
+    supported: Vec<String>,
+  },
+  #[error("failed to resolve library dependencies:\n{0}")]
+  UnresolvedDependencies(String),
   #[error("failed during a file operation: {0}")]
   Io(#[from] io::Error),
   #[error("failed during a glob pattern operation: {0}")]
@@ -202,4 +1418,577 @@ enum ConfigError {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use std::path::Path;
+
+  fn touch(path: &Path) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, "").unwrap();
+  }
+
+  /// Lays out a minimal `arduino_home` and `external_libraries_home`
+  fn fixture(root: &Path) -> ConfigSerialize {
+    let arduino_home = root.join("arduino15");
+    let external_libraries_home = root.join("Arduino");
+    let package = arduino_home.join("packages").join("arduino");
+    let core = package.join("hardware").join("avr").join("1.8.6");
+    touch(
+      &package
+        .join("tools")
+        .join("avr-gcc")
+        .join("7.3.0-atmel3.6.1-arduino7")
+        .join("bin")
+        .join("avr-gcc"),
+    );
+    for file in [
+      "main.cpp",
+      "wiring.c",
+      "HardwareSerial.cpp",
+      "wiring_pulse.S",
+      "Arduino.h",
+    ] {
+      touch(&core.join("cores").join("arduino").join(file));
+    }
+    touch(&core.join("variants/standard/pins_arduino.h"));
+    fs::write(
+      core.join("boards.txt"),
+      "uno.name=Arduino Uno\n\
+       uno.build.mcu=atmega328p\n\
+       uno.build.f_cpu=16000000L\n\
+       uno.build.board=AVR_UNO\n\
+       uno.build.core=arduino\n\
+       uno.build.variant=standard\n",
+    )
+    .unwrap();
+    let wire = core.join("libraries/Wire/src");
+    for file in ["Wire.cpp", "Wire.h", "utility/twi.c", "utility/twi.h"] {
+      touch(&wire.join(file));
+    }
+    fs::write(
+      core.join("libraries/Wire/library.properties"),
+      "name=Wire\narchitectures=avr\ndot_a_linkage=true\n",
+    )
+    .unwrap();
+    touch(&external_libraries_home.join("Servo/src/Servo.cxx"));
+    fs::write(
+      external_libraries_home.join("Servo/library.properties"),
+      "name=Servo\narchitectures=*\n",
+    )
+    .unwrap();
+    ConfigSerialize {
+      arduino_home: Some(arduino_home),
+      external_libraries_home: Some(external_libraries_home),
+      board: None,
+      board_options: HashMap::new(),
+      core_version: Some("1.8.6".into()),
+      variant: Some("standard".into()),
+      avr_gcc_version: None,
+      arduino_libraries: vec!["Wire".into()],
+      external_libraries: vec!["Servo".into()],
+      sources: Vec::new(),
+      sketch: None,
+      discover: None,
+      exclude: Vec::new(),
+      definitions: HashMap::new(),
+      flags: Vec::new(),
+      c_flags: Vec::new(),
+      cpp_flags: Vec::new(),
+      asm_flags: Vec::new(),
+      link_flags: Vec::new(),
+      bindgen_lists: BindgenLists {
+        allowlist_function: Vec::new(),
+        allowlist_type: Vec::new(),
+        blocklist_function: Vec::new(),
+        blocklist_type: Vec::new(),
+      },
+      object_cache: ObjectCache::Project,
+      profiles: HashMap::new(),
+      optimize_size: None,
+    }
+  }
+
+  fn file_names<'a>(files: impl IntoIterator<Item = &'a PathBuf>) -> Vec<&'a str> {
+    files
+      .into_iter()
+      .map(|f| f.file_name().unwrap().to_str().unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn collects_sources_by_language() {
+    let root = tempfile::tempdir().unwrap();
+    let config = Config::try_from(fixture(root.path())).unwrap();
+    let sources = &config.sources;
+    assert_eq!(
+      file_names(sources.iter().flat_map(|set| &set.c_files)),
+      ["wiring.c", "twi.c"]
+    );
+    assert_eq!(
+      file_names(sources.iter().flat_map(|set| &set.cpp_files)),
+      ["HardwareSerial.cpp", "Wire.cpp", "Servo.cxx"]
+    );
+    assert_eq!(
+      file_names(sources.iter().flat_map(|set| &set.asm_files)),
+      ["wiring_pulse.S"]
+    );
+    assert_eq!(
+      sources
+        .iter()
+        .map(|set| (set.name.as_str(), set.whole_archive))
+        .collect::<Vec<_>>(),
+      [("core", false), ("Wire", false), ("Servo", true)]
+    );
+  }
+
+  #[test]
+  fn compiles_flat_libraries_without_recursing() {
+    let root = tempfile::tempdir().unwrap();
+    let mut config = fixture(root.path());
+    let legacy = root.path().join("Arduino").join("Legacy");
+    for file in [
+      "Legacy.cpp",
+      "utility/helper.c",
+      "utility/nested/skipped.c",
+      "examples/Blink/Blink.cpp",
+    ] {
+      touch(&legacy.join(file));
+    }
+    config.external_libraries = vec!["Legacy".into()];
+    let config = Config::try_from(config).unwrap();
+    let set = config.sources.last().unwrap();
+    assert_eq!(set.name, "Legacy");
+    assert_eq!(file_names(&set.cpp_files), ["Legacy.cpp"]);
+    assert_eq!(file_names(&set.c_files), ["helper.c"]);
+    assert_eq!(set.includes, [legacy.join("utility")]);
+    assert!(config.includes.contains(&legacy));
+  }
+
+  #[test]
+  fn links_precompiled_archives_for_the_mcu() {
+    let root = tempfile::tempdir().unwrap();
+    let mut config = fixture(root.path());
+    let libraries = root.path().join("Arduino");
+    for (name, precompiled) in [("Full", "full"), ("Partial", "true")] {
+      let library = libraries.join(name);
+      touch(&library.join("src").join(format!("{}.cpp", name)));
+      touch(
+        &library
+          .join("src/atmega328p")
+          .join(format!("lib{}.a", name)),
+      );
+      fs::write(
+        library.join("library.properties"),
+        format!("name={}\nprecompiled={}\n", name, precompiled),
+      )
+      .unwrap();
+    }
+    config.external_libraries = vec!["Full".into(), "Partial".into()];
+    config.flags = vec!["-mmcu=atmega328p".into()];
+    let precompiled = Config::try_from(config).unwrap();
+    assert_eq!(
+      file_names(&precompiled.archives),
+      ["libFull.a", "libPartial.a"]
+    );
+    assert_eq!(
+      precompiled
+        .sources
+        .iter()
+        .map(|set| set.name.as_str())
+        .collect::<Vec<_>>(),
+      ["core", "Wire", "Partial"]
+    );
+
+    // Without an archive for the mcu, full falls back to the sources
+    let mut config = fixture(root.path());
+    config.external_libraries = vec!["Full".into()];
+    config.flags = vec!["-mmcu=atmega2560".into()];
+    let fallback = Config::try_from(config).unwrap();
+    assert!(fallback.archives.is_empty());
+    assert_eq!(fallback.sources.last().unwrap().name, "Full");
+  }
+
+  #[test]
+  fn loads_config_by_extension() {
+    let root = tempfile::tempdir().unwrap();
+    let yaml = root.path().join("rarduino.yaml");
+    fs::write(
+      &yaml,
+      r#"
+arduino_home: $HOME/.arduino15
+external_libraries_home: $HOME/Arduino
+core_version: 1.8.6
+board: arduino:avr:uno
+variant: standard
+avr_gcc_version: 7.3.0-atmel3.6.1-arduino7
+arduino_libraries: [Wire]
+external_libraries: []
+definitions:
+  F_CPU: 16000000L
+flags: ['-mmcu=atmega328p']
+bindgen_lists:
+  allowlist_function: [digitalWrite]
+  allowlist_type: []
+  blocklist_function: []
+  blocklist_type: []
+"#,
+    )
+    .unwrap();
+    let config = ConfigSerialize::from_file(&yaml).unwrap();
+    assert_eq!(config.definitions["F_CPU"], "16000000L");
+    assert_eq!(config.bindgen_lists.allowlist_function, ["digitalWrite"]);
+
+    let ini = root.path().join("rarduino.ini");
+    fs::write(&ini, "").unwrap();
+    assert!(matches!(
+      ConfigSerialize::from_file(&ini),
+      Err(ConfigError::UnknownFormat(_))
+    ));
+  }
+
+  #[test]
+  fn loads_config_from_manifest_metadata() {
+    let root = tempfile::tempdir().unwrap();
+    let manifest = root.path().join("Cargo.toml");
+    fs::write(
+      &manifest,
+      r#"
+[package]
+name = "firmware"
+version = "0.1.0"
+
+[package.metadata.rarduino]
+arduino_home = "$HOME/.arduino15"
+external_libraries_home = "$HOME/Arduino"
+core_version = "1.8.6"
+variant = "standard"
+avr_gcc_version = "7.3.0-atmel3.6.1-arduino7"
+arduino_libraries = []
+external_libraries = []
+flags = ["-mmcu=atmega328p"]
+
+[package.metadata.rarduino.definitions]
+F_CPU = "16000000L"
+
+[package.metadata.rarduino.bindgen_lists]
+allowlist_function = ["millis"]
+allowlist_type = []
+blocklist_function = []
+blocklist_type = []
+"#,
+    )
+    .unwrap();
+    let config = ConfigSerialize::from_manifest(&manifest).unwrap();
+    assert_eq!(config.flags, ["-mmcu=atmega328p"]);
+    assert_eq!(config.bindgen_lists.allowlist_function, ["millis"]);
+
+    fs::write(&manifest, "[package]\nname = \"firmware\"\n").unwrap();
+    assert!(matches!(
+      ConfigSerialize::from_manifest(&manifest),
+      Err(ConfigError::NoManifestMetadata(_))
+    ));
+  }
+
+  #[test]
+  fn explicit_values_override_board() {
+    let root = tempfile::tempdir().unwrap();
+    let mut serialized = fixture(root.path());
+    serialized.board = Some("arduino:avr:uno".into());
+    serialized.variant = None;
+    serialized
+      .definitions
+      .insert("F_CPU".into(), "8000000L".into());
+    serialized
+      .definitions
+      .insert("ARDUINO".into(), "10819".into());
+    serialized.flags = vec!["-mmcu=atmega328".into()];
+    let config = Config::try_from(serialized).unwrap();
+    assert_eq!(config.definitions["F_CPU"], "8000000L");
+    assert_eq!(config.definitions["ARDUINO"], "10819");
+    assert_eq!(config.definitions["ARDUINO_AVR_UNO"], "1");
+    assert_eq!(config.definitions["ARDUINO_ARCH_AVR"], "1");
+    assert_eq!(config.flags, ["-mmcu=atmega328"]);
+    assert!(config
+      .includes
+      .iter()
+      .any(|dir| dir.ends_with("variants/standard")));
+  }
+
+  #[test]
+  fn applies_library_settings_to_their_sources_only() {
+    let root = tempfile::tempdir().unwrap();
+    let mut config = fixture(root.path());
+    config.definitions.insert("BUFFER".into(), "64".into());
+    config.arduino_libraries = serde_yaml::from_str(
+      "- name: Wire\n\
+         \x20 definitions: {BUFFER: '32', TWI_FREQ: 400000L}\n\
+         \x20 flags: ['-Wno-unused']\n\
+         \x20 exclude: ['src/utility/*']\n",
+    )
+    .unwrap();
+    let config = Config::try_from(config).unwrap();
+    let wire = &config.sources[1];
+    assert_eq!(wire.name, "Wire");
+    assert_eq!(file_names(&wire.cpp_files), ["Wire.cpp"]);
+    assert!(wire.c_files.is_empty());
+    assert_eq!(wire.definitions["BUFFER"], "32");
+    assert_eq!(wire.definitions["TWI_FREQ"], "400000L");
+    assert_eq!(wire.flags, ["-Wno-unused"]);
+    let servo = &config.sources[2];
+    assert!(servo.definitions.is_empty() && servo.flags.is_empty());
+    assert_eq!(config.definitions["BUFFER"], "64");
+  }
+
+  #[test]
+  fn layers_language_flags_on_shared_flags() {
+    let root = tempfile::tempdir().unwrap();
+    let mut serialized = fixture(root.path());
+    let core = root
+      .path()
+      .join("arduino15/packages/arduino/hardware/avr/1.8.6");
+    fs::write(
+      core.join("platform.txt"),
+      "compiler.c.flags=-std=gnu11\n\
+       compiler.cpp.flags=-std=gnu++11\n\
+       compiler.S.flags=-x assembler-with-cpp\n\
+       compiler.c.elf.flags=-Os\n",
+    )
+    .unwrap();
+    serialized.board = Some("arduino:avr:uno".into());
+    serialized.flags = vec!["-Wall".into()];
+    serialized.cpp_flags = vec!["-std=gnu++17".into(), "-fno-threadsafe-statics".into()];
+    serialized.link_flags = vec!["-Wl,--gc-sections".into()];
+    let config = Config::try_from(serialized).unwrap();
+    let layer = |language: &LanguageFlags| language.layer(&["-Og".into()], &config.flags);
+    assert_eq!(
+      layer(&config.c_flags),
+      ["-std=gnu11", "-Og", "-mmcu=atmega328p", "-Wall"]
+    );
+    assert_eq!(
+      layer(&config.cpp_flags),
+      [
+        "-std=gnu++11",
+        "-Og",
+        "-mmcu=atmega328p",
+        "-Wall",
+        "-std=gnu++17",
+        "-fno-threadsafe-statics"
+      ]
+    );
+    assert_eq!(
+      layer(&config.asm_flags),
+      [
+        "-x",
+        "assembler-with-cpp",
+        "-Og",
+        "-mmcu=atmega328p",
+        "-Wall"
+      ]
+    );
+    assert_eq!(config.link_flags, ["-Os", "-Wl,--gc-sections"]);
+  }
+
+  #[test]
+  fn optimizes_size_when_asked() {
+    let root = tempfile::tempdir().unwrap();
+    let mut serialized = fixture(root.path());
+    serialized.board = Some("arduino:avr:uno".into());
+    serialized.flags = vec!["-Wall".into()];
+    serialized.link_flags = vec!["-Wl,--relax".into()];
+    serialized.optimize_size = Some(OptimizeSize { lto: true });
+    let config = Config::try_from(serialized).unwrap();
+    assert_eq!(
+      config.flags,
+      [
+        "-mmcu=atmega328p",
+        "-ffunction-sections",
+        "-fdata-sections",
+        "-flto",
+        "-ffat-lto-objects",
+        "-Wall"
+      ]
+    );
+    assert_eq!(
+      config.link_flags,
+      [
+        "-Wl,--gc-sections",
+        "-flto",
+        "-mmcu=atmega328p",
+        "-Wl,--relax"
+      ]
+    );
+  }
+
+  #[test]
+  fn pairs_avr_gcc_with_core_from_package_index() {
+    let root = tempfile::tempdir().unwrap();
+    let serialized = fixture(root.path());
+    let arduino_home = serialized.arduino_home.clone().unwrap();
+    let paired = "7.3.0-atmel3.6.1-arduino5";
+    touch(
+      &arduino_home
+        .join("packages/arduino/tools/avr-gcc")
+        .join(paired)
+        .join("bin/avr-gcc"),
+    );
+    fs::write(
+      arduino_home.join("package_index.json"),
+      format!(
+        r#"{{"packages": [{{"name": "arduino", "platforms": [{{
+          "architecture": "avr", "version": "1.8.6", "toolsDependencies": [
+            {{"packager": "arduino", "name": "avr-gcc", "version": "{}"}}
+          ]}}]}}]}}"#,
+        paired
+      ),
+    )
+    .unwrap();
+    let config = Config::try_from(serialized).unwrap();
+    assert!(config.avr_gcc.ends_with(format!("{}/bin/avr-gcc", paired)));
+  }
+
+  #[test]
+  fn rejects_unsupported_architecture() {
+    let root = tempfile::tempdir().unwrap();
+    let serialized = fixture(root.path());
+    fs::write(
+      serialized
+        .external_libraries_home
+        .as_ref()
+        .unwrap()
+        .join("Servo/library.properties"),
+      "name=Servo\narchitectures=sam,samd\n",
+    )
+    .unwrap();
+    assert!(matches!(
+      Config::try_from(serialized),
+      Err(ConfigError::UnsupportedArchitecture { .. })
+    ));
+  }
+
+  #[test]
+  fn exposes_top_level_library_headers() {
+    let root = tempfile::tempdir().unwrap();
+    let config = Config::try_from(fixture(root.path())).unwrap();
+    assert_eq!(file_names(&config.library_headers), ["Wire.h"]);
+  }
+
+  #[test]
+  fn watches_inputs_and_env_vars() {
+    let root = tempfile::tempdir().unwrap();
+    let config = Config::try_from(fixture(root.path())).unwrap();
+    let watched = file_names(&config.rerun_if_changed);
+    for file in [
+      "library.properties",
+      "Wire.cpp",
+      "twi.c",
+      "twi.h",
+      "Servo.cxx",
+      "Arduino.h",
+      "pins_arduino.h",
+    ] {
+      assert!(watched.contains(&file), "{} in {:?}", file, watched);
+    }
+    assert!(!watched.contains(&"main.cpp"));
+    // Both homes are explicit, so arduino-cli's directories aren't looked up
+    assert!(!config
+      .rerun_if_env_changed
+      .contains("ARDUINO_DIRECTORIES_DATA"));
+
+    if !cfg!(windows) {
+      assert_eq!(
+        referenced_env_vars("$HOME/.arduino15:${DATA_DIR}/x/${LIBS:fallback}"),
+        ["HOME", "DATA_DIR", "LIBS"]
+      );
+    }
+  }
+
+  #[test]
+  fn watches_sketch_and_driver_headers_and_indexes() {
+    let root = tempfile::tempdir().unwrap();
+    let mut config = fixture(root.path());
+    for file in [
+      "Blink/Blink.ino",
+      "Blink/config.h",
+      "Blink/src/pins.h",
+      "vendor/driver/driver.c",
+      "vendor/driver/registers.h",
+    ] {
+      touch(&root.path().join(file));
+    }
+    fs::write(
+      root.path().join("arduino15/package_index.json"),
+      "{\"packages\": []}",
+    )
+    .unwrap();
+    config.sketch = Some(root.path().join("Blink"));
+    config.sources = vec![root
+      .path()
+      .join("vendor/driver/*.c")
+      .to_string_lossy()
+      .to_string()];
+    let config = Config::try_from(config).unwrap();
+    let watched = file_names(&config.rerun_if_changed);
+    for file in ["config.h", "pins.h", "registers.h", "package_index.json"] {
+      assert!(watched.contains(&file), "{} in {:?}", file, watched);
+    }
+  }
+
+  #[test]
+  fn links_sketch_as_a_whole() {
+    let root = tempfile::tempdir().unwrap();
+    let mut config = fixture(root.path());
+    let dir = root.path().join("Blink");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("Blink.ino"), "ISR(TIMER1_COMPA_vect) {}\n").unwrap();
+    fs::write(dir.join("src/blink.c"), "").unwrap();
+    config.sketch = Some(dir);
+    let config = Config::try_from(config).unwrap();
+    let sketch = config
+      .sources
+      .iter()
+      .find(|set| set.name == "sketch")
+      .unwrap();
+    assert!(sketch.whole_archive);
+    assert!(sketch.sketch.is_some());
+    assert_eq!(file_names(&sketch.c_files), ["blink.c"]);
+  }
+
+  #[test]
+  fn compiles_crate_sources() {
+    let root = tempfile::tempdir().unwrap();
+    let mut config = fixture(root.path());
+    let csrc = root.path().join("csrc");
+    let vendor = root.path().join("vendor");
+    for file in [
+      "csrc/shim.cpp",
+      "csrc/shim.h",
+      "csrc/detail/impl.c",
+      "csrc/detail/impl.h",
+      "vendor/driver.c",
+      "vendor/driver.h",
+      "vendor/notes.txt",
+    ] {
+      touch(&root.path().join(file));
+    }
+    config.sources = vec![
+      csrc.to_string_lossy().to_string(),
+      vendor.join("*").to_string_lossy().to_string(),
+    ];
+    let config = Config::try_from(config).unwrap();
+    let set = config.sources.last().unwrap();
+    assert_eq!((set.name.as_str(), set.whole_archive), ("sources", true));
+    assert_eq!(file_names(&set.c_files), ["impl.c", "driver.c"]);
+    assert_eq!(file_names(&set.cpp_files), ["shim.cpp"]);
+    assert_eq!(
+      file_names(&config.library_headers),
+      ["Wire.h", "shim.h", "driver.h"]
+    );
+    assert!(config.includes.contains(&csrc) && config.includes.contains(&vendor));
+    assert!(wrapper_contents(&config).ends_with(&format!(
+      "#include \"{}\"\n\
+       extern \"C\" {{\n\
+       #include \"{}\"\n\
+       }}\n",
+      csrc.join("shim.h").display(),
+      vendor.join("driver.h").display()
+    )));
+  }
 }
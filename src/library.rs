@@ -0,0 +1,297 @@
+use crate::properties::Properties;
+use crate::ConfigError;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How a library's precompiled archives replace its sources
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Precompiled {
+  /// No precompiled archives
+  #[default]
+  False,
+  /// The archive is linked and the sources are still compiled
+  True,
+  /// The archive replaces the sources when there is one for the mcu
+  Full,
+}
+
+/// Metadata of a library, from its `library.properties`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LibraryProperties {
+  pub name: String,
+  pub version: String,
+  /// Names of other libraries this one needs, possibly with a version constraint
+  pub depends: Vec<String>,
+  /// Architectures the library supports, `*` for all of them
+  pub architectures: Vec<String>,
+  /// Headers users are meant to include
+  pub includes: Vec<String>,
+  pub precompiled: Precompiled,
+  /// Extra linker flags, usually for a precompiled archive
+  pub ldflags: Vec<String>,
+  /// Whether the library is archived before linking instead of linked object by object
+  pub dot_a_linkage: bool,
+}
+
+impl LibraryProperties {
+  /// Reads a `library.properties` file
+  pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+    let path = path.as_ref();
+    Self::parse(&Properties::load(path)?, path)
+  }
+
+  fn parse(properties: &Properties, path: &Path) -> Result<Self, ConfigError> {
+    let list = |key: &str| -> Vec<String> {
+      properties
+        .get(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+    };
+    let name = properties
+      .get("name")
+      .filter(|name| !name.is_empty())
+      .ok_or(ConfigError::LibraryNoName(path.to_path_buf()))?;
+    let precompiled = match properties.get("precompiled") {
+      Some("true") => Precompiled::True,
+      Some("full") => Precompiled::Full,
+      _ => Precompiled::False,
+    };
+    let ldflags = properties.get("ldflags").unwrap_or_default();
+    let architectures = match list("architectures") {
+      architectures if architectures.is_empty() => vec!["*".to_string()],
+      architectures => architectures,
+    };
+    Ok(LibraryProperties {
+      name: name.to_string(),
+      version: properties.get("version").unwrap_or_default().to_string(),
+      depends: list("depends"),
+      architectures,
+      includes: list("includes"),
+      precompiled,
+      ldflags: shlex::split(ldflags).ok_or(ConfigError::UnbalancedQuotes(ldflags.to_string()))?,
+      dot_a_linkage: properties.get("dot_a_linkage") == Some("true"),
+    })
+  }
+
+  /// Whether the library can be built for `architecture`
+  pub fn supports(&self, architecture: &str) -> bool {
+    self
+      .architectures
+      .iter()
+      .any(|supported| supported == "*" || supported.eq_ignore_ascii_case(architecture))
+  }
+}
+
+/// Source layout of a library
+#[derive(Debug, PartialEq)]
+pub(crate) enum Layout {
+  /// 1.5 layout, everything below `src/` is compiled
+  Recursive(PathBuf),
+  /// 1.0 layout, the sources in the library root and in `utility/` are compiled, but not
+  /// the ones in their subdirectories
+  Flat {
+    root: PathBuf,
+    /// Only on the include path of the library itself
+    utility: Option<PathBuf>,
+  },
+}
+
+/// An installed library, with its `library.properties` if it has one
+#[derive(Debug)]
+pub(crate) struct Library {
+  pub(crate) dir: PathBuf,
+  pub(crate) properties: Option<LibraryProperties>,
+}
+
+impl Library {
+  pub(crate) fn open(dir: PathBuf) -> Result<Self, ConfigError> {
+    if !dir.is_dir() {
+      return Err(ConfigError::NoLibrary(dir));
+    }
+    let properties_path = dir.join("library.properties");
+    let properties = if properties_path.exists() {
+      Some(LibraryProperties::load(&properties_path)?)
+    } else {
+      None
+    };
+    Ok(Library { dir, properties })
+  }
+
+  /// `name` from `library.properties`, or the directory name for libraries without one
+  pub(crate) fn name(&self) -> String {
+    match &self.properties {
+      Some(properties) => properties.name.clone(),
+      None => self
+        .dir
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default(),
+    }
+  }
+
+  /// Fails if `library.properties` rules out `architecture`
+  pub(crate) fn validate(&self, architecture: &str) -> Result<(), ConfigError> {
+    match &self.properties {
+      Some(properties) if !properties.supports(architecture) => {
+        Err(ConfigError::UnsupportedArchitecture {
+          library: properties.name.clone(),
+          architecture: architecture.to_string(),
+          supported: properties.architectures.clone(),
+        })
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Where the library's sources are, following the library specification
+  ///
+  /// Libraries without `library.properties` are legacy ones, flat even when they have `src/`
+  pub(crate) fn layout(&self) -> Layout {
+    let src = self.dir.join("src");
+    if src.is_dir() && self.properties.is_some() {
+      Layout::Recursive(src)
+    } else {
+      let utility = self.dir.join("utility");
+      Layout::Flat {
+        root: self.dir.clone(),
+        utility: utility.is_dir().then_some(utility),
+      }
+    }
+  }
+
+  /// Directory users include the library's headers from
+  pub(crate) fn src_root(&self) -> PathBuf {
+    match self.layout() {
+      Layout::Recursive(src) => src,
+      Layout::Flat { root, .. } => root,
+    }
+  }
+
+  /// Directory of the archives built for `mcu`, `src/{mcu}`, if the library is precompiled and
+  /// ships one
+  pub(crate) fn precompiled_dir(&self, mcu: &str) -> Option<PathBuf> {
+    let precompiled = self
+      .properties
+      .as_ref()
+      .map_or(Precompiled::False, |properties| properties.precompiled);
+    let dir = self.dir.join("src").join(mcu);
+    (precompiled != Precompiled::False && dir.is_dir()).then_some(dir)
+  }
+
+  pub(crate) fn dot_a_linkage(&self) -> bool {
+    self
+      .properties
+      .as_ref()
+      .is_some_and(|properties| properties.dot_a_linkage)
+  }
+}
+
+/// Every library found in the search directories, in the order they were given
+pub(crate) fn installed(search_dirs: &[PathBuf]) -> Result<Vec<Library>, ConfigError> {
+  let mut libraries = Vec::new();
+  for dir in search_dirs.iter().filter(|dir| dir.is_dir()) {
+    let mut entries = fs::read_dir(dir)?
+      .map(|entry| entry.map(|entry| entry.path()))
+      .collect::<Result<Vec<PathBuf>, _>>()?;
+    entries.sort();
+    // Libraries that fail to load can't be resolved anyway, and may never be needed
+    libraries.extend(
+      entries
+        .into_iter()
+        .filter_map(|entry| Library::open(entry).ok()),
+    );
+  }
+  Ok(libraries)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_library_properties() {
+    let properties = Properties::parse(
+      "name=Adafruit SSD1306\n\
+       version=2.5.7\n\
+       depends=Adafruit GFX Library, Adafruit BusIO (>=1.9.0)\n\
+       architectures=avr, megaavr\n\
+       includes=Adafruit_SSD1306.h\n\
+       precompiled=full\n\
+       ldflags=-lssd1306 -Wl,--undefined=init\n\
+       dot_a_linkage=true\n",
+    );
+    let library = LibraryProperties::parse(&properties, Path::new("library.properties")).unwrap();
+    assert_eq!(
+      library,
+      LibraryProperties {
+        name: "Adafruit SSD1306".into(),
+        version: "2.5.7".into(),
+        depends: vec![
+          "Adafruit GFX Library".into(),
+          "Adafruit BusIO (>=1.9.0)".into()
+        ],
+        architectures: vec!["avr".into(), "megaavr".into()],
+        includes: vec!["Adafruit_SSD1306.h".into()],
+        precompiled: Precompiled::Full,
+        ldflags: vec!["-lssd1306".into(), "-Wl,--undefined=init".into()],
+        dot_a_linkage: true,
+      }
+    );
+    assert!(library.supports("AVR"));
+    assert!(!library.supports("sam"));
+  }
+
+  #[test]
+  fn defaults_to_every_architecture() {
+    let properties = Properties::parse("name=Servo\n");
+    let library = LibraryProperties::parse(&properties, Path::new("library.properties")).unwrap();
+    assert!(library.supports("avr"));
+    assert_eq!(library.precompiled, Precompiled::False);
+    assert!(!library.dot_a_linkage);
+    assert!(matches!(
+      LibraryProperties::parse(&Properties::parse("version=1.0\n"), Path::new("x")),
+      Err(ConfigError::LibraryNoName(_))
+    ));
+  }
+
+  #[test]
+  fn detects_layout() {
+    let root = tempfile::tempdir().unwrap();
+    let flat = root.path().join("Flat");
+    fs::create_dir_all(flat.join("utility")).unwrap();
+    let flat = Library::open(flat).unwrap();
+    assert_eq!(
+      flat.layout(),
+      Layout::Flat {
+        root: flat.dir.clone(),
+        utility: Some(flat.dir.join("utility")),
+      }
+    );
+    assert_eq!(flat.src_root(), flat.dir);
+    // utility/ is an ordinary directory next to src/
+    let recursive = root.path().join("Recursive");
+    fs::create_dir_all(recursive.join("src")).unwrap();
+    fs::create_dir_all(recursive.join("utility")).unwrap();
+    fs::write(recursive.join("library.properties"), "name=Recursive\n").unwrap();
+    let recursive = Library::open(recursive).unwrap();
+    assert_eq!(
+      recursive.layout(),
+      Layout::Recursive(recursive.dir.join("src"))
+    );
+    // Without library.properties src/ is an ordinary directory too
+    let legacy = root.path().join("Legacy");
+    fs::create_dir_all(legacy.join("src")).unwrap();
+    let legacy = Library::open(legacy).unwrap();
+    assert_eq!(
+      legacy.layout(),
+      Layout::Flat {
+        root: legacy.dir.clone(),
+        utility: None,
+      }
+    );
+  }
+}
@@ -0,0 +1,145 @@
+use crate::ConfigError;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Overrides the arduino-cli data directory, i.e. the arduino home
+const DATA_ENV: &str = "ARDUINO_DIRECTORIES_DATA";
+/// Overrides the arduino-cli user directory, i.e. the sketchbook
+const USER_ENV: &str = "ARDUINO_DIRECTORIES_USER";
+
+#[derive(Debug, Default, Deserialize)]
+struct CliConfig {
+  #[serde(default)]
+  directories: CliDirectories,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CliDirectories {
+  data: Option<PathBuf>,
+  user: Option<PathBuf>,
+}
+
+/// Where arduino-cli puts its data and user directories when not configured otherwise
+fn default_directories(env: &dyn Fn(&str) -> Option<String>) -> (Option<PathBuf>, Option<PathBuf>) {
+  if cfg!(target_os = "windows") {
+    let data = env("LOCALAPPDATA").map(|dir| Path::new(&dir).join("Arduino15"));
+    let user = env("USERPROFILE").map(|dir| Path::new(&dir).join("Documents").join("Arduino"));
+    (data, user)
+  } else if cfg!(target_os = "macos") {
+    let home = env("HOME").map(PathBuf::from);
+    let data = home
+      .as_ref()
+      .map(|home| home.join("Library").join("Arduino15"));
+    let user = home.map(|home| home.join("Documents").join("Arduino"));
+    (data, user)
+  } else {
+    let home = env("HOME").map(PathBuf::from);
+    let data = home.as_ref().map(|home| home.join(".arduino15"));
+    let user = home.map(|home| home.join("Arduino"));
+    (data, user)
+  }
+}
+
+/// Directories to try, in order, for the arduino home and for the sketchbook
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Candidates {
+  pub(crate) data: Vec<PathBuf>,
+  pub(crate) user: Vec<PathBuf>,
+  /// The `arduino-cli.yaml` read, if there was one
+  pub(crate) cli_config: Option<PathBuf>,
+}
+
+impl Candidates {
+  /// The `ARDUINO_DIRECTORIES_*` variables, then the `directories` in `arduino-cli.yaml`, then
+  /// the per-OS defaults
+  pub(crate) fn new(env: &dyn Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+    let env_data = env(DATA_ENV).map(PathBuf::from);
+    let env_user = env(USER_ENV).map(PathBuf::from);
+    let (default_data, default_user) = default_directories(env);
+
+    let mut cli_config = CliConfig::default();
+    let mut cli_config_file = None;
+    for dir in env_data.iter().chain(&default_data) {
+      let file = dir.join("arduino-cli.yaml");
+      if file.exists() {
+        cli_config = serde_yaml::from_str(&fs::read_to_string(&file)?)?;
+        cli_config_file = Some(file);
+        break;
+      }
+    }
+
+    let candidates = |dirs: [Option<PathBuf>; 3]| {
+      let mut candidates: Vec<PathBuf> = Vec::new();
+      for dir in dirs.into_iter().flatten() {
+        if !candidates.contains(&dir) {
+          candidates.push(dir);
+        }
+      }
+      candidates
+    };
+    Ok(Candidates {
+      data: candidates([env_data, cli_config.directories.data, default_data]),
+      user: candidates([env_user, cli_config.directories.user, default_user]),
+      cli_config: cli_config_file,
+    })
+  }
+}
+
+/// The first candidate that exists, or every candidate tried
+pub(crate) fn first_existing(candidates: Vec<PathBuf>) -> Result<PathBuf, Vec<PathBuf>> {
+  match candidates.iter().find(|dir| dir.is_dir()) {
+    Some(dir) => Ok(dir.clone()),
+    None => Err(candidates),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  #[test]
+  fn prefers_env_then_cli_config_then_defaults() {
+    let root = tempfile::tempdir().unwrap();
+    let home = root.path().to_path_buf();
+    let (default_data, _) = default_directories(&|key| {
+      (key == "HOME" || key == "LOCALAPPDATA" || key == "USERPROFILE")
+        .then(|| home.to_string_lossy().to_string())
+    });
+    let default_data = default_data.unwrap();
+    fs::create_dir_all(&default_data).unwrap();
+    fs::write(
+      default_data.join("arduino-cli.yaml"),
+      "directories:\n  data: /opt/arduino/data\n  user: /opt/arduino/user\n",
+    )
+    .unwrap();
+
+    let mut vars = HashMap::new();
+    for key in ["HOME", "LOCALAPPDATA", "USERPROFILE"] {
+      vars.insert(key, home.to_string_lossy().to_string());
+    }
+    vars.insert(USER_ENV, "/srv/sketchbook".to_string());
+    let candidates = Candidates::new(&|key| vars.get(key).cloned()).unwrap();
+    assert_eq!(
+      candidates.data,
+      [PathBuf::from("/opt/arduino/data"), default_data.clone()]
+    );
+    assert_eq!(
+      candidates.user[..2],
+      [
+        PathBuf::from("/srv/sketchbook"),
+        PathBuf::from("/opt/arduino/user"),
+      ]
+    );
+    assert_eq!(
+      candidates.cli_config,
+      Some(default_data.join("arduino-cli.yaml"))
+    );
+    assert_eq!(first_existing(candidates.data), Ok(default_data));
+    assert_eq!(
+      first_existing(vec![PathBuf::from("/nonexistent")]),
+      Err(vec![PathBuf::from("/nonexistent")])
+    );
+  }
+}
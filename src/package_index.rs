@@ -0,0 +1,141 @@
+use crate::ConfigError;
+use glob::glob;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+struct PackageIndex {
+  packages: Vec<Package>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Package {
+  name: String,
+  #[serde(default)]
+  platforms: Vec<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+  architecture: String,
+  version: String,
+  #[serde(default, rename = "toolsDependencies")]
+  tools_dependencies: Vec<ToolDependency>,
+}
+
+/// A tool a platform release needs, e.g. `arduino:avr-gcc@7.3.0-atmel3.6.1-arduino7`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct ToolDependency {
+  /// Vendor the tool is installed under, the directory under `packages`
+  pub(crate) packager: String,
+  pub(crate) name: String,
+  pub(crate) version: String,
+}
+
+impl ToolDependency {
+  /// Where the tool is installed to under `arduino_home`
+  pub(crate) fn path(&self, arduino_home: &Path) -> PathBuf {
+    arduino_home
+      .join("packages")
+      .join(&self.packager)
+      .join("tools")
+      .join(&self.name)
+      .join(&self.version)
+  }
+}
+
+/// Index files arduino-cli keeps in `arduino_home`, `package_index.json` first
+pub(crate) fn index_files(arduino_home: &Path) -> Result<Vec<PathBuf>, ConfigError> {
+  let mut files: Vec<PathBuf> = Vec::new();
+  let main_index = arduino_home.join("package_index.json");
+  if main_index.exists() {
+    files.push(main_index);
+  }
+  let pattern = arduino_home.join("package_*_index.json");
+  let pattern = pattern
+    .to_str()
+    .ok_or(ConfigError::ConvertFailed(pattern.clone()))?;
+  for file in glob(pattern)? {
+    files.push(file?);
+  }
+  Ok(files)
+}
+
+/// The tools a platform release depends on, from the first index that lists it
+pub(crate) fn tools_dependencies(
+  arduino_home: &Path,
+  vendor: &str,
+  architecture: &str,
+  version: &str,
+) -> Result<Option<Vec<ToolDependency>>, ConfigError> {
+  for file in index_files(arduino_home)? {
+    let index: PackageIndex = serde_json::from_str(&fs::read_to_string(&file)?)
+      .map_err(|e| ConfigError::PackageIndex(file.clone(), e))?;
+    let platform = index
+      .packages
+      .into_iter()
+      .filter(|package| package.name == vendor)
+      .flat_map(|package| package.platforms)
+      .find(|platform| platform.architecture == architecture && platform.version == version);
+    if let Some(platform) = platform {
+      return Ok(Some(platform.tools_dependencies));
+    }
+  }
+  Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn finds_platform_tools() {
+    let root = tempfile::tempdir().unwrap();
+    fs::write(
+      root.path().join("package_index.json"),
+      r#"{"packages": [{"name": "arduino", "platforms": [
+        {"architecture": "avr", "version": "1.8.5", "toolsDependencies": [
+          {"packager": "arduino", "name": "avr-gcc", "version": "7.3.0-atmel3.6.1-arduino5"}
+        ]},
+        {"architecture": "avr", "version": "1.8.6", "toolsDependencies": [
+          {"packager": "arduino", "name": "avr-gcc", "version": "7.3.0-atmel3.6.1-arduino7"},
+          {"packager": "arduino", "name": "avrdude", "version": "6.3.0-arduino17"},
+          {"packager": "arduino", "name": "arduinoOTA", "version": "1.3.0"}
+        ]}
+      ]}]}"#,
+    )
+    .unwrap();
+    fs::write(
+      root.path().join("package_example_index.json"),
+      r#"{"packages": [{"name": "example", "platforms": [
+        {"architecture": "avr", "version": "1.0.0", "toolsDependencies": [
+          {"packager": "arduino", "name": "avr-gcc", "version": "5.4.0-atmel3.6.1-arduino2"}
+        ]}
+      ]}]}"#,
+    )
+    .unwrap();
+
+    let tools = tools_dependencies(root.path(), "arduino", "avr", "1.8.6")
+      .unwrap()
+      .unwrap();
+    assert_eq!(
+      tools
+        .iter()
+        .map(|tool| (tool.name.as_str(), tool.version.as_str()))
+        .collect::<Vec<_>>(),
+      [
+        ("avr-gcc", "7.3.0-atmel3.6.1-arduino7"),
+        ("avrdude", "6.3.0-arduino17"),
+        ("arduinoOTA", "1.3.0"),
+      ]
+    );
+    let tools = tools_dependencies(root.path(), "example", "avr", "1.0.0")
+      .unwrap()
+      .unwrap();
+    assert_eq!(tools[0].version, "5.4.0-atmel3.6.1-arduino2");
+    assert!(tools_dependencies(root.path(), "arduino", "avr", "1.6.0")
+      .unwrap()
+      .is_none());
+  }
+}
@@ -0,0 +1,90 @@
+use crate::properties::Properties;
+use crate::ConfigError;
+
+/// The platform's LTO flags, dropped because `-fno-fat-lto-objects` leaves no regular code
+/// for a link without `-flto`, LTO is opted into per profile or with `optimize_size` instead
+const LTO_FLAGS: [&str; 3] = ["-flto", "-fno-fat-lto-objects", "-fuse-linker-plugin"];
+
+/// Compiler flags from a core's `platform.txt`, expanded for one board
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PlatformFlags {
+  /// `compiler.c.flags` and `compiler.c.extra_flags`
+  pub(crate) c: Vec<String>,
+  /// `compiler.cpp.flags` and `compiler.cpp.extra_flags`
+  pub(crate) cpp: Vec<String>,
+  /// `compiler.S.flags` and `compiler.S.extra_flags`
+  pub(crate) asm: Vec<String>,
+  /// `compiler.c.elf.flags` and `compiler.c.elf.extra_flags`
+  pub(crate) elf: Vec<String>,
+}
+
+impl PlatformFlags {
+  /// Expands the flag properties, dropping the LTO flags
+  pub(crate) fn new(properties: &Properties) -> Result<Self, ConfigError> {
+    let flags = |recipe: &str| -> Result<Vec<String>, ConfigError> {
+      let mut flags = Vec::new();
+      for key in [
+        format!("compiler.{}.flags", recipe),
+        format!("compiler.{}.extra_flags", recipe),
+      ] {
+        let Some(value) = properties.get(&key) else {
+          continue;
+        };
+        let value = properties.expand(value);
+        flags.extend(
+          shlex::split(&value)
+            .ok_or(ConfigError::UnbalancedQuotes(value))?
+            .into_iter()
+            .filter(|flag| !LTO_FLAGS.contains(&flag.as_str())),
+        );
+      }
+      Ok(flags)
+    };
+    Ok(PlatformFlags {
+      c: flags("c")?,
+      cpp: flags("cpp")?,
+      asm: flags("S")?,
+      elf: flags("c.elf")?,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Excerpt of the platform.txt shipped with arduino:avr 1.8.6
+  const PLATFORM_TXT: &str = "\
+compiler.warning_flags=-w
+compiler.c.flags=-c -g -Os {compiler.warning_flags} -std=gnu11 -ffunction-sections -fdata-sections -MMD -flto -fno-fat-lto-objects
+compiler.c.elf.flags={compiler.warning_flags} -Os -g -flto -fuse-linker-plugin -Wl,--gc-sections
+compiler.S.flags=-c -g -x assembler-with-cpp -flto -MMD
+compiler.cpp.flags=-c -g -Os {compiler.warning_flags} -std=gnu++11 -fpermissive -fno-exceptions -ffunction-sections -fdata-sections -fno-threadsafe-statics -Wno-error=narrowing -MMD -flto
+compiler.c.extra_flags=
+compiler.c.elf.extra_flags=
+compiler.S.extra_flags=
+compiler.cpp.extra_flags=-DEXTRA={build.mcu}
+build.mcu=atmega328p
+";
+
+  fn split(flags: &str) -> Vec<String> {
+    flags.split_whitespace().map(String::from).collect()
+  }
+
+  #[test]
+  fn expands_platform_flags() {
+    let flags = PlatformFlags::new(&Properties::parse(PLATFORM_TXT)).unwrap();
+    assert_eq!(
+      flags,
+      PlatformFlags {
+        c: split("-c -g -Os -w -std=gnu11 -ffunction-sections -fdata-sections -MMD"),
+        cpp: split(
+          "-c -g -Os -w -std=gnu++11 -fpermissive -fno-exceptions -ffunction-sections \
+           -fdata-sections -fno-threadsafe-statics -Wno-error=narrowing -MMD -DEXTRA=atmega328p"
+        ),
+        asm: split("-c -g -x assembler-with-cpp -MMD"),
+        elf: split("-w -Os -g -Wl,--gc-sections"),
+      }
+    );
+  }
+}
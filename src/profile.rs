@@ -0,0 +1,148 @@
+use crate::ProfileSettings;
+use std::collections::HashMap;
+
+/// Whether cargo's `DEBUG` asks for debug info, it's `false` or `0` when it doesn't
+fn debug_info(value: &str) -> bool {
+  !matches!(value, "" | "false" | "0" | "none")
+}
+
+/// The gcc optimization flag closest to cargo's `OPT_LEVEL`
+///
+/// Levels 0 and 1 get `-Og` rather than `-O0`, the delays of avr-libc don't work without
+/// optimization
+fn optimization(opt_level: &str) -> &'static str {
+  match opt_level {
+    "0" | "1" => "-Og",
+    "s" | "z" => "-Os",
+    _ => "-O2",
+  }
+}
+
+/// Optimization and debug info flags matching `OPT_LEVEL` and `DEBUG`, read through `env`
+///
+/// They go before the user's flags, so an explicit `-O` or `-g` there wins
+fn cargo_flags(env: &dyn Fn(&str) -> Option<String>) -> Vec<String> {
+  let mut flags: Vec<String> = env("OPT_LEVEL")
+    .map(|level| optimization(&level).to_string())
+    .into_iter()
+    .collect();
+  match env("DEBUG").map(|value| debug_info(&value)) {
+    Some(true) => flags.push("-g".to_string()),
+    // Platforms pass -g themselves
+    Some(false) => flags.push("-g0".to_string()),
+    None => {}
+  }
+  flags
+}
+
+/// Flags and definitions for the cargo profile being built
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Profile {
+  /// From [`cargo_flags`], they go before the user's flags
+  pub(crate) cargo_flags: Vec<String>,
+  /// From the config's section for the profile, like the flags below, which go after every
+  /// other one
+  pub(crate) definitions: HashMap<String, String>,
+  pub(crate) c_flags: Vec<String>,
+  pub(crate) cpp_flags: Vec<String>,
+  pub(crate) asm_flags: Vec<String>,
+  pub(crate) link_flags: Vec<String>,
+}
+
+impl Profile {
+  /// The flags matching `OPT_LEVEL` and `DEBUG` and the section of `profiles` named after
+  /// `PROFILE`, all read through `env`
+  ///
+  /// LTO objects keep their regular code next to the LTO bytecode, so links without `-flto`
+  /// still work
+  pub(crate) fn resolve(
+    env: &dyn Fn(&str) -> Option<String>,
+    profiles: &HashMap<String, ProfileSettings>,
+  ) -> Self {
+    let settings = env("PROFILE")
+      .and_then(|name| profiles.get(&name))
+      .cloned()
+      .unwrap_or_default();
+    let mut flags: Vec<String> = settings
+      .opt_level
+      .map(|level| format!("-O{}", level))
+      .into_iter()
+      .collect();
+    match settings.debug {
+      Some(true) => flags.push("-g".to_string()),
+      Some(false) => flags.push("-g0".to_string()),
+      None => {}
+    }
+    let mut link_flags = Vec::new();
+    if settings.lto == Some(true) {
+      flags.extend(["-flto".to_string(), "-ffat-lto-objects".to_string()]);
+      link_flags.push("-flto".to_string());
+    }
+    flags.extend(settings.flags);
+    link_flags.extend(settings.link_flags);
+    let layer = |language: Vec<String>| flags.iter().cloned().chain(language).collect();
+    Profile {
+      cargo_flags: cargo_flags(env),
+      definitions: settings.definitions,
+      c_flags: layer(settings.c_flags),
+      cpp_flags: layer(settings.cpp_flags),
+      asm_flags: layer(settings.asm_flags),
+      link_flags,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn maps_cargo_settings_and_overrides() {
+    let env = |release: bool| {
+      move |key: &str| match key {
+        "PROFILE" => Some(if release { "release" } else { "debug" }.to_string()),
+        "OPT_LEVEL" => Some(if release { "3" } else { "0" }.to_string()),
+        "DEBUG" => Some(if release { "false" } else { "true" }.to_string()),
+        _ => None,
+      }
+    };
+    assert_eq!(cargo_flags(&env(false)), ["-Og", "-g"]);
+    assert_eq!(cargo_flags(&env(true)), ["-O2", "-g0"]);
+    assert!(cargo_flags(&|_| None).is_empty());
+    // Without a section for the profile, only the automatic flags apply
+    let profiles = HashMap::new();
+    let debug = Profile::resolve(&env(false), &profiles);
+    assert_eq!(
+      debug,
+      Profile {
+        cargo_flags: vec!["-Og".to_string(), "-g".to_string()],
+        ..Default::default()
+      }
+    );
+
+    let profiles: HashMap<String, ProfileSettings> = serde_yaml::from_str(
+      "release:\n\
+       \x20 opt_level: s\n\
+       \x20 lto: true\n\
+       \x20 definitions: {NDEBUG: '1'}\n\
+       \x20 flags: ['-Wall']\n\
+       \x20 cpp_flags: ['-fno-rtti']\n\
+       \x20 link_flags: ['-Wl,--relax']\n",
+    )
+    .unwrap();
+    let release = Profile::resolve(&env(true), &profiles);
+    assert_eq!(release.cargo_flags, ["-O2", "-g0"]);
+    assert_eq!(
+      release.cpp_flags,
+      ["-Os", "-flto", "-ffat-lto-objects", "-Wall", "-fno-rtti"]
+    );
+    assert_eq!(
+      release.c_flags,
+      ["-Os", "-flto", "-ffat-lto-objects", "-Wall"]
+    );
+    assert_eq!(release.definitions["NDEBUG"], "1");
+    assert_eq!(release.link_flags, ["-flto", "-Wl,--relax"]);
+    // Sections of other profiles don't apply
+    assert_eq!(Profile::resolve(&env(false), &profiles), debug);
+  }
+}
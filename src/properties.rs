@@ -0,0 +1,160 @@
+use crate::ConfigError;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Suffix of keys that only apply to the host OS, e.g. `tools.avrdude.path.linux`
+#[cfg(target_os = "windows")]
+const OS_SUFFIX: &str = ".windows";
+#[cfg(target_os = "macos")]
+const OS_SUFFIX: &str = ".macosx";
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const OS_SUFFIX: &str = ".linux";
+
+/// How many levels of `{key}` references [`Properties::expand`] follows
+const MAX_EXPANSION_DEPTH: usize = 10;
+
+/// `key=value` pairs as found in `boards.txt`, `platform.txt` and `library.properties`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct Properties(BTreeMap<String, String>);
+
+impl Properties {
+  /// Parses the contents of a property file
+  ///
+  /// Blank lines and lines starting with `#` are ignored, keys and values are trimmed and
+  /// keys ending in the host OS suffix override the key without it
+  pub(crate) fn parse(contents: &str) -> Self {
+    let mut properties = BTreeMap::new();
+    let mut os_specific = Vec::new();
+    for line in contents.lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let Some((key, value)) = line.split_once('=') else {
+        continue;
+      };
+      let (key, value) = (key.trim(), value.trim());
+      if let Some(key) = key.strip_suffix(OS_SUFFIX) {
+        os_specific.push((key.to_string(), value.to_string()));
+      }
+      properties.insert(key.to_string(), value.to_string());
+    }
+    properties.extend(os_specific);
+    Properties(properties)
+  }
+
+  /// Reads and parses a property file
+  pub(crate) fn load(path: &Path) -> Result<Self, ConfigError> {
+    Ok(Self::parse(&fs::read_to_string(path)?))
+  }
+
+  pub(crate) fn get(&self, key: &str) -> Option<&str> {
+    self.0.get(key).map(String::as_str)
+  }
+
+  pub(crate) fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+    self.0.insert(key.into(), value.into());
+  }
+
+  pub(crate) fn keys(&self) -> impl Iterator<Item = &str> {
+    self.0.keys().map(String::as_str)
+  }
+
+  /// Overrides properties with the ones in `other`
+  pub(crate) fn extend(&mut self, other: Properties) {
+    self.0.extend(other.0);
+  }
+
+  /// Replaces every `{key}` in `value` with that property, recursively
+  ///
+  /// References to unknown keys are left as is, like arduino-cli does
+  pub(crate) fn expand(&self, value: &str) -> String {
+    let mut value = value.to_string();
+    for _ in 0..MAX_EXPANSION_DEPTH {
+      let expanded = self.expand_once(&value);
+      if expanded == value {
+        break;
+      }
+      value = expanded;
+    }
+    value
+  }
+
+  fn expand_once(&self, value: &str) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+      let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+        break;
+      };
+      expanded.push_str(&rest[..start]);
+      match self.get(&rest[start + 1..end]) {
+        Some(property) => expanded.push_str(property),
+        None => expanded.push_str(&rest[start..=end]),
+      }
+      rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+  }
+
+  /// Every property below `prefix.`, with the prefix stripped
+  pub(crate) fn subtree(&self, prefix: &str) -> Properties {
+    let prefix = format!("{}.", prefix);
+    Properties(
+      self
+        .0
+        .iter()
+        .filter_map(|(key, value)| {
+          key
+            .strip_prefix(&prefix)
+            .map(|key| (key.to_string(), value.clone()))
+        })
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_and_takes_subtrees() {
+    let properties = Properties::parse(
+      "# comment\n\
+       \n\
+       uno.name=Arduino Uno\n\
+       uno.build.mcu = atmega328p\n\
+       uno.build.extra_flags=-DFOO=1\n\
+       uno.upload.tool=avrdude\n\
+       uno.upload.tool.linux=avrdude_linux\n\
+       uno.upload.tool.windows=avrdude_windows\n\
+       uno.upload.tool.macosx=avrdude_macosx\n",
+    );
+    let uno = properties.subtree("uno");
+    assert_eq!(uno.get("name"), Some("Arduino Uno"));
+    assert_eq!(uno.get("build.mcu"), Some("atmega328p"));
+    assert_eq!(uno.get("build.extra_flags"), Some("-DFOO=1"));
+    assert_eq!(
+      uno.get("upload.tool"),
+      Some(format!("avrdude_{}", &OS_SUFFIX[1..]).as_str())
+    );
+    assert_eq!(uno.subtree("build").get("mcu"), Some("atmega328p"));
+  }
+
+  #[test]
+  fn expands_references() {
+    let properties = Properties::parse(
+      "compiler.warning_flags=-w\n\
+       compiler.c.flags=-c -Os {compiler.warning_flags} {unknown}\n\
+       recipe={compiler.c.flags} -mmcu={build.mcu}\n\
+       build.mcu=atmega328p\n",
+    );
+    assert_eq!(
+      properties.expand("{recipe} {"),
+      "-c -Os -w {unknown} -mmcu=atmega328p {"
+    );
+  }
+}
@@ -0,0 +1,153 @@
+use crate::ConfigError;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Puts every function and variable in its own section, so the linker can drop unused ones
+const SECTION_FLAGS: [&str; 2] = ["-ffunction-sections", "-fdata-sections"];
+
+/// Compile flags of the size optimized mode, LTO objects keep their regular code so links
+/// without `-flto` still work
+pub(crate) fn compile_flags(lto: bool) -> Vec<String> {
+  let mut flags: Vec<String> = SECTION_FLAGS.map(String::from).to_vec();
+  if lto {
+    flags.extend(["-flto".to_string(), "-ffat-lto-objects".to_string()]);
+  }
+  flags
+}
+
+/// Link flags of the size optimized mode, `-mmcu` aside
+pub(crate) fn link_flags(lto: bool) -> Vec<String> {
+  let mut flags = vec!["-Wl,--gc-sections".to_string()];
+  if lto {
+    flags.push("-flto".to_string());
+  }
+  flags
+}
+
+/// Flash an ELF takes, text plus data, from the berkeley output of avr-size
+fn flash(output: &str) -> Option<u64> {
+  let mut sizes = output
+    .lines()
+    .nth(1)?
+    .split_whitespace()
+    .map(|size| size.parse::<u64>().ok());
+  Some(sizes.next()?? + sizes.next()??)
+}
+
+/// Links the compiled archives without the Rust code, to see how much flash they take
+pub(crate) struct TrialLink<'a> {
+  pub(crate) avr_gcc: &'a Path,
+  pub(crate) avr_size: &'a Path,
+  /// Flags every link gets, usually `-mmcu`
+  pub(crate) flags: Vec<String>,
+  /// Linked first, they reference what's taken from the archives
+  pub(crate) objects: Vec<PathBuf>,
+  /// Linked as a whole
+  pub(crate) whole_archives: Vec<PathBuf>,
+  /// Only the members something references are linked
+  pub(crate) archives: Vec<PathBuf>,
+  pub(crate) out_dir: &'a Path,
+}
+
+impl TrialLink<'_> {
+  /// Flash taken when linked with `flags` into `out_dir/{name}.elf`
+  ///
+  /// What the Rust code provides, `setup` and `loop` among others, is left unresolved
+  pub(crate) fn flash(&self, name: &str, flags: &[String]) -> Result<u64, ConfigError> {
+    let elf = self.out_dir.join(format!("{}.elf", name));
+    let mut link = Command::new(self.avr_gcc);
+    link
+      .args(&self.flags)
+      .args(flags)
+      .arg("-Wl,--unresolved-symbols=ignore-all")
+      .arg("-o")
+      .arg(&elf)
+      .args(&self.objects);
+    if !self.whole_archives.is_empty() {
+      link
+        .arg("-Wl,--whole-archive")
+        .args(&self.whole_archives)
+        .arg("-Wl,--no-whole-archive");
+    }
+    let output = link
+      .args(&self.archives)
+      .output()
+      .map_err(|e| ConfigError::RunTool(self.avr_gcc.to_path_buf(), e))?;
+    if !output.status.success() {
+      return Err(ConfigError::TrialLink(
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+      ));
+    }
+    let output = Command::new(self.avr_size)
+      .arg(&elf)
+      .output()
+      .map_err(|e| ConfigError::RunTool(self.avr_size.to_path_buf(), e))?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    flash(&output).ok_or_else(|| ConfigError::SizeOutput(output.into_owned()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  #[test]
+  fn reads_flash_from_avr_size() {
+    assert_eq!(
+      flash(
+        "   text\t   data\t    bss\t    dec\t    hex\tfilename\n\
+         \x20   924\t     16\t      9\t    949\t    3b5\tsize.elf\n"
+      ),
+      Some(940)
+    );
+    assert_eq!(flash("avr-size: 'size.elf': No such file\n"), None);
+  }
+
+  fn run(dir: &Path, command: &[&str]) {
+    let status = Command::new(command[0])
+      .args(&command[1..])
+      .current_dir(dir)
+      .status()
+      .unwrap();
+    assert!(status.success(), "{:?}", command);
+  }
+
+  #[test]
+  fn measures_what_main_reaches() {
+    let root = tempfile::tempdir().unwrap();
+    let dir = root.path();
+    fs::write(
+      dir.join("main.c"),
+      "int used(void);\nint main(void) { return used(); }\n",
+    )
+    .unwrap();
+    fs::write(
+      dir.join("core.c"),
+      "int used(void) { return 1; }\n\
+       const char table[8192] = {1};\n\
+       int unused(void) { return table[1]; }\n",
+    )
+    .unwrap();
+    // Measured with the host toolchain
+    let mut compile = vec!["cc", "-c", "main.c", "core.c"];
+    compile.extend(SECTION_FLAGS);
+    run(dir, &compile);
+    run(dir, &["ar", "rcs", "libcore.a", "core.o"]);
+    let link = |objects: Vec<PathBuf>| TrialLink {
+      avr_gcc: Path::new("cc"),
+      avr_size: Path::new("size"),
+      flags: Vec::new(),
+      objects,
+      whole_archives: Vec::new(),
+      archives: vec![dir.join("libcore.a")],
+      out_dir: dir,
+    };
+    let with_main = link(vec![dir.join("main.o")]);
+    let plain = with_main.flash("plain", &[]).unwrap();
+    let optimized = with_main.flash("optimized", &link_flags(false)).unwrap();
+    assert!(plain >= optimized + 8192, "{} {}", plain, optimized);
+    // Without main nothing is taken from the archive
+    assert!(link(Vec::new()).flash("empty", &[]).unwrap() + 8192 <= plain);
+  }
+}
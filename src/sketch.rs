@@ -0,0 +1,313 @@
+use crate::ConfigError;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Extensions of the files that make up a sketch, concatenated into one translation unit
+const SKETCH_EXTENSIONS: [&str; 2] = ["ino", "pde"];
+
+/// Words that start a statement or a type definition rather than a function definition
+const NOT_FUNCTIONS: [&str; 14] = [
+  "if",
+  "else",
+  "for",
+  "while",
+  "do",
+  "switch",
+  "return",
+  "struct",
+  "class",
+  "union",
+  "enum",
+  "namespace",
+  "typedef",
+  "template",
+];
+
+/// A sketch directory, as the IDE lays it out
+#[derive(Debug)]
+pub(crate) struct Sketch {
+  pub(crate) dir: PathBuf,
+  name: String,
+  /// `{name}.ino` first, then the other sketch files in alphabetical order
+  pub(crate) ino_files: Vec<PathBuf>,
+}
+
+impl Sketch {
+  pub(crate) fn open(dir: PathBuf) -> Result<Self, ConfigError> {
+    let name = dir
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string())
+      .unwrap_or_default();
+    let mut ino_files = Vec::new();
+    if dir.is_dir() {
+      for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path
+          .extension()
+          .is_some_and(|ext| SKETCH_EXTENSIONS.iter().any(|sketch| ext == *sketch))
+        {
+          ino_files.push(path);
+        }
+      }
+    }
+    ino_files.sort();
+    let main = ino_files
+      .iter()
+      .position(|file| file.file_stem().is_some_and(|stem| *stem == *name))
+      .ok_or(ConfigError::NoSketch(dir.clone()))?;
+    let main = ino_files.remove(main);
+    ino_files.insert(0, main);
+    Ok(Sketch {
+      dir,
+      name,
+      ino_files,
+    })
+  }
+
+  /// Writes the preprocessed sketch to `out_dir/sketch/{name}.ino.cpp`
+  pub(crate) fn write(&self, out_dir: &Path) -> Result<PathBuf, ConfigError> {
+    let dir = out_dir.join("sketch");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.ino.cpp", self.name));
+    fs::write(&path, self.preprocess()?)?;
+    Ok(path)
+  }
+
+  /// Concatenates the sketch files, then adds `#include <Arduino.h>`, prototypes for every
+  /// function before the first one is defined, and `#line` directives pointing at the sketch
+  /// files, like arduino-builder
+  fn preprocess(&self) -> Result<String, ConfigError> {
+    let mut merged = String::new();
+    // File index and line number, from 1, of every merged line
+    let mut origins = Vec::new();
+    for (index, file) in self.ino_files.iter().enumerate() {
+      for (number, line) in fs::read_to_string(file)?.lines().enumerate() {
+        merged.push_str(line);
+        merged.push('\n');
+        origins.push((index, number + 1));
+      }
+    }
+    let line_directive = |merged_line: usize| {
+      let (file, number) = origins[merged_line];
+      format!(
+        "#line {} \"{}\"\n",
+        number,
+        self.ino_files[file].to_string_lossy().replace('\\', "\\\\")
+      )
+    };
+    let functions = functions(&blank(&merged));
+    let mut out = String::from("#include <Arduino.h>\n");
+    for (index, line) in merged.lines().enumerate() {
+      if functions.first().is_some_and(|(_, first)| *first == index) {
+        for (prototype, line) in &functions {
+          out.push_str(&line_directive(*line));
+          out.push_str(prototype);
+          out.push_str(";\n");
+        }
+        out.push_str(&line_directive(index));
+      } else if origins[index].1 == 1 {
+        out.push_str(&line_directive(index));
+      }
+      out.push_str(line);
+      out.push('\n');
+    }
+    Ok(out)
+  }
+}
+
+/// `source` with comments, string and character literals and preprocessor directives
+/// replaced by spaces, line breaks are kept so offsets and line numbers still match
+fn blank(source: &str) -> String {
+  let bytes = source.as_bytes();
+  let mut out = bytes.to_vec();
+  let erase = |out: &mut [u8], from: usize, to: usize| {
+    for byte in &mut out[from..to] {
+      if *byte != b'\n' {
+        *byte = b' ';
+      }
+    }
+  };
+  let mut i = 0;
+  let mut line_start = true;
+  while i < bytes.len() {
+    let rest = &bytes[i..];
+    let end = if rest.starts_with(b"//") {
+      i + rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len())
+    } else if rest.starts_with(b"/*") {
+      i + rest[2..]
+        .windows(2)
+        .position(|window| window == b"*/")
+        .map_or(rest.len(), |end| end + 4)
+    } else if bytes[i] == b'"' || bytes[i] == b'\'' {
+      let mut end = i + 1;
+      while end < bytes.len() && bytes[end] != bytes[i] && bytes[end] != b'\n' {
+        end += if bytes[end] == b'\\' { 2 } else { 1 };
+      }
+      line_start = false;
+      (end + 1).min(bytes.len())
+    } else if bytes[i] == b'#' && line_start {
+      // Up to the end of the line, following backslash continuations
+      let mut end = i;
+      while end < bytes.len() && !(bytes[end] == b'\n' && bytes[end - 1] != b'\\') {
+        end += 1;
+      }
+      end
+    } else {
+      match bytes[i] {
+        b'\n' => line_start = true,
+        byte if byte.is_ascii_whitespace() => {}
+        _ => line_start = false,
+      }
+      i += 1;
+      continue;
+    };
+    erase(&mut out, i, end);
+    i = end;
+  }
+  // Only ASCII was written over whole comments and literals, so this is still valid UTF-8
+  String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The declaration `text`, with whitespace collapsed, if it's a free function
+fn signature(text: &str) -> Option<String> {
+  let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+  let open = text.find('(')?;
+  // Default arguments can't be repeated in the definition, and initializers aren't functions
+  if !text.ends_with(')') || text.contains('=') {
+    return None;
+  }
+  let head = text[..open].trim_end();
+  let name = head
+    .rsplit([' ', '*', '&'])
+    .next()
+    .filter(|name| !name.is_empty())?;
+  let return_type = head[..head.len() - name.len()].trim();
+  // Methods defined outside their class, and macros such as `ISR(TIMER1_COMPA_vect)`
+  if return_type.is_empty()
+    || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    || NOT_FUNCTIONS.contains(&head.split(' ').next().unwrap_or_default())
+  {
+    return None;
+  }
+  Some(text)
+}
+
+/// Prototypes of the functions defined at the top level of `code`, which must have gone
+/// through [`blank`], with the line each definition starts on, minus the ones already
+/// declared
+fn functions(code: &str) -> Vec<(String, usize)> {
+  let mut functions = Vec::new();
+  let mut declarations = Vec::new();
+  let mut depth = 0usize;
+  let mut parens = 0usize;
+  let mut start = 0;
+  for (i, c) in code.char_indices() {
+    match c {
+      '(' => parens += 1,
+      ')' => parens = parens.saturating_sub(1),
+      '{' if parens == 0 => {
+        if depth == 0 {
+          if let Some(prototype) = signature(&code[start..i]) {
+            let offset = code[start..i].len() - code[start..i].trim_start().len();
+            let line = code[..start + offset].matches('\n').count();
+            functions.push((prototype, line));
+          }
+        }
+        depth += 1;
+      }
+      '}' if parens == 0 => {
+        depth = depth.saturating_sub(1);
+        if depth == 0 {
+          start = i + 1;
+        }
+      }
+      ';' if depth == 0 && parens == 0 => {
+        declarations.extend(signature(&code[start..i]));
+        start = i + 1;
+      }
+      _ => {}
+    }
+  }
+  functions.retain(|(prototype, _)| !declarations.contains(prototype));
+  functions
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn finds_function_definitions() {
+    let code = blank(
+      "#include <Servo.h>\n\
+       #define LED \\\n  13\n\
+       struct Point { int x; int y; };\n\
+       // void commented() {}\n\
+       const char *message = \"void quoted() {\";\n\
+       Point origin() { return {0, 0}; }\n\
+       void helper(int pin);\n\
+       static unsigned long *buffer(const Point &p,\n    int length) {\n\
+       \x20 if (p.x) { return 0; }\n\
+       }\n\
+       ISR(TIMER1_COMPA_vect) {}\n\
+       void Servo::write(int value) {}\n\
+       void defaults(int pin = 13) {}\n\
+       void helper(int pin) {}\n",
+    );
+    assert_eq!(
+      functions(&code),
+      [
+        ("Point origin()".to_string(), 6),
+        (
+          "static unsigned long *buffer(const Point &p, int length)".to_string(),
+          8
+        ),
+      ]
+    );
+  }
+
+  #[test]
+  fn preprocesses_sketch_files() {
+    let root = tempfile::tempdir().unwrap();
+    let dir = root.path().join("Blink");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+      dir.join("Blink.ino"),
+      "#include \"config.h\"\n\nvoid setup() {\n  blink();\n}\n\nvoid loop() {}\n",
+    )
+    .unwrap();
+    fs::write(dir.join("Another.ino"), "void blink() {}\n").unwrap();
+    fs::write(dir.join("config.h"), "").unwrap();
+
+    let sketch = Sketch::open(dir.clone()).unwrap();
+    let main = dir.join("Blink.ino").to_string_lossy().to_string();
+    let another = dir.join("Another.ino").to_string_lossy().to_string();
+    assert_eq!(
+      sketch.preprocess().unwrap(),
+      format!(
+        "#include <Arduino.h>\n\
+         #line 1 \"{main}\"\n\
+         #include \"config.h\"\n\
+         \n\
+         #line 3 \"{main}\"\n\
+         void setup();\n\
+         #line 7 \"{main}\"\n\
+         void loop();\n\
+         #line 1 \"{another}\"\n\
+         void blink();\n\
+         #line 3 \"{main}\"\n\
+         void setup() {{\n\
+         \x20 blink();\n\
+         }}\n\
+         \n\
+         void loop() {{}}\n\
+         #line 1 \"{another}\"\n\
+         void blink() {{}}\n"
+      )
+    );
+    assert!(matches!(
+      Sketch::open(root.path().to_path_buf()),
+      Err(ConfigError::NoSketch(_))
+    ));
+  }
+}
@@ -0,0 +1,169 @@
+use crate::ConfigError;
+use semver::{Version, VersionReq};
+use std::fs;
+use std::path::Path;
+
+/// Picks one of the versions installed as subdirectories of `dir`
+///
+/// `requested` may be an exact directory name or a semver requirement such as `^1.8`, when it
+/// is `None` the highest installed version is used. A complete version must be installed as
+/// it is, only partial versions and ones with an operator are requirements. Pre-release tags
+/// like the `-atmel3.6.1` in avr-gcc versions are ignored when matching a requirement
+pub(crate) fn select_version(
+  dir: &Path,
+  requested: Option<&str>,
+  what: &'static str,
+) -> Result<String, ConfigError> {
+  let mut installed = Vec::new();
+  if dir.is_dir() {
+    for entry in fs::read_dir(dir)? {
+      let entry = entry?;
+      if entry.file_type()?.is_dir() {
+        if let Some(name) = entry.file_name().to_str() {
+          installed.push(name.to_string());
+        }
+      }
+    }
+  }
+  installed.sort();
+  if let Some(requested) = requested {
+    if installed.iter().any(|version| version == requested) {
+      return Ok(requested.to_string());
+    }
+  }
+
+  let no_match = |installed| ConfigError::NoMatchingVersion {
+    what,
+    requested: requested.unwrap_or("*").to_string(),
+    dir: dir.to_path_buf(),
+    installed,
+  };
+  if requested.is_some_and(|requested| Version::parse(requested).is_ok()) {
+    return Err(no_match(installed));
+  }
+  let requirement = match requested {
+    Some(requested) => Some(
+      VersionReq::parse(requested)
+        .map_err(|e| ConfigError::InvalidVersionReq(requested.to_string(), e))?,
+    ),
+    None => None,
+  };
+  installed
+    .iter()
+    .filter_map(|name| Some((Version::parse(name).ok()?, name)))
+    .filter(|(version, _)| {
+      let release = Version::new(version.major, version.minor, version.patch);
+      requirement
+        .as_ref()
+        .is_none_or(|requirement| requirement.matches(&release))
+    })
+    .max_by(|(a, _), (b, _)| a.cmp(b))
+    .map(|(_, name)| name.clone())
+    .ok_or_else(|| no_match(installed.clone()))
+}
+
+/// Parses versions like `1.9` or `2.0.1-beta` the way library.properties uses them, filling in
+/// missing components with zeros
+pub(crate) fn parse_lenient(version: &str) -> Option<Version> {
+  let version = version.trim();
+  if let Ok(version) = Version::parse(version) {
+    return Some(version);
+  }
+  let release = version.split(['-', '+']).next()?;
+  let mut components = release.split('.').map(|part| part.parse::<u64>().ok());
+  let major = components.next()??;
+  let minor = components.next().unwrap_or(Some(0))?;
+  let patch = components.next().unwrap_or(Some(0))?;
+  if components.next().is_some() {
+    return None;
+  }
+  Some(Version::new(major, minor, patch))
+}
+
+/// Checks a version against a library.properties `depends` constraint such as `>=1.9.0`
+/// or `>=1.0 && <2.0`, terms are joined with `&&` and alternatives with `||`
+pub(crate) fn satisfies(version: &str, constraint: &str) -> bool {
+  let Some(version) = parse_lenient(version) else {
+    return false;
+  };
+  constraint.split("||").any(|alternative| {
+    alternative.split("&&").all(|term| {
+      let term = term.trim();
+      let (matches, bound): (fn(std::cmp::Ordering) -> bool, &str) =
+        if let Some(bound) = term.strip_prefix(">=") {
+          (|ordering| ordering.is_ge(), bound)
+        } else if let Some(bound) = term.strip_prefix("<=") {
+          (|ordering| ordering.is_le(), bound)
+        } else if let Some(bound) = term.strip_prefix('>') {
+          (|ordering| ordering.is_gt(), bound)
+        } else if let Some(bound) = term.strip_prefix('<') {
+          (|ordering| ordering.is_lt(), bound)
+        } else {
+          (|ordering| ordering.is_eq(), term.trim_start_matches('='))
+        };
+      parse_lenient(bound).is_some_and(|bound| matches(version.cmp(&bound)))
+    })
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn installed(versions: &[&str]) -> tempfile::TempDir {
+    let root = tempfile::tempdir().unwrap();
+    for version in versions {
+      fs::create_dir(root.path().join(version)).unwrap();
+    }
+    root
+  }
+
+  #[test]
+  fn selects_best_match() {
+    let cores = installed(&["1.8.3", "1.8.6", "1.6.23"]);
+    let select = |requested| select_version(cores.path(), requested, "core").unwrap();
+    assert_eq!(select(None), "1.8.6");
+    assert_eq!(select(Some("1.8.3")), "1.8.3");
+    assert_eq!(select(Some("~1.6")), "1.6.23");
+    assert_eq!(select(Some("^1.8")), "1.8.6");
+    assert_eq!(select(Some("1.8")), "1.8.6");
+    // A complete version is never swapped for another one
+    assert!(matches!(
+      select_version(cores.path(), Some("1.8.4"), "core"),
+      Err(ConfigError::NoMatchingVersion { .. })
+    ));
+  }
+
+  #[test]
+  fn matches_avr_gcc_versions() {
+    let gccs = installed(&["5.4.0-atmel3.6.1-arduino2", "7.3.0-atmel3.6.1-arduino7"]);
+    let select = |requested| select_version(gccs.path(), requested, "avr-gcc");
+    assert_eq!(select(None).unwrap(), "7.3.0-atmel3.6.1-arduino7");
+    assert_eq!(select(Some("^5")).unwrap(), "5.4.0-atmel3.6.1-arduino2");
+    assert!(matches!(
+      select(Some("7.3.0-atmel3.6.1-arduino5")),
+      Err(ConfigError::NoMatchingVersion { .. })
+    ));
+    match select(Some("^8")) {
+      Err(ConfigError::NoMatchingVersion { installed, .. }) => {
+        assert_eq!(
+          installed,
+          ["5.4.0-atmel3.6.1-arduino2", "7.3.0-atmel3.6.1-arduino7"]
+        )
+      }
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+
+  #[test]
+  fn checks_depends_constraints() {
+    assert_eq!(parse_lenient("1.9"), Some(Version::new(1, 9, 0)));
+    assert!(satisfies("1.10.0", ">=1.9.0"));
+    assert!(!satisfies("1.7", ">=1.9.0"));
+    assert!(satisfies("1.0.0", "=1.0"));
+    assert!(satisfies("1.5", ">=1.0 && <2.0"));
+    assert!(!satisfies("2.1", ">=1.0 && <2.0"));
+    assert!(satisfies("3.0", "<2.0 || >=3.0"));
+    assert!(!satisfies("unknown", ">=1.0"));
+  }
+}